@@ -0,0 +1,644 @@
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, IsTerminal, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    terminal,
+};
+use structopt::StructOpt;
+use tokio::{
+    sync::{mpsc::channel, Semaphore},
+    task::JoinSet,
+};
+
+use crate::{
+    kodansha::{
+        self, user::download_dir, Choice, ExistingFiles, Library, Manifest, OutputFormat,
+        PageCache, Queue, QueueState, SaveOptions, Saved, Settings, SortOrder, SyncReason,
+    },
+    Credentials, User, Volume,
+};
+
+/// Read by `login` when `--password` isn't passed, so it stays out of shell history
+const PASSWORD_VAR: &str = "K_DOWNLOAD_PASSWORD";
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "k-download", about = "Offline backup of your Kodansha library")]
+pub struct Options {
+    /// Overrides the Kodansha API url, e.g. to point at a local mock server
+    #[structopt(long, global = true)]
+    pub api_url: Option<String>,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Saves your credentials and fetches a fresh token
+    Login {
+        #[structopt(short, long)]
+        username: String,
+        /// Ends up in your shell history, leave it out to read the password from
+        /// `K_DOWNLOAD_PASSWORD` or stdin instead
+        #[structopt(short, long)]
+        password: Option<String>,
+    },
+    /// Lists every volume and chapter in your library as `id<TAB>series<TAB>name`
+    List {
+        /// added (API order), series, number, pages or downloaded. Defaults to the sort in the settings
+        #[structopt(long)]
+        sort: Option<SortOrder>,
+    },
+    /// Lists the volumes and chapters in your library that were never downloaded, like `list`
+    Missing,
+    /// Lists everything downloaded so far as `id<TAB>format<TAB>downloaded at<TAB>path`
+    History,
+    /// Downloads the volumes and chapters with the given ids
+    Download {
+        #[structopt(required = true)]
+        ids: Vec<u16>,
+        /// Saves chapters of a series that follow each other as one book
+        #[structopt(long)]
+        merge_chapters: bool,
+        #[structopt(flatten)]
+        output: OutputOptions,
+    },
+    /// Queues and downloads every volume that isn't in the destination or the manifest yet
+    Sync {
+        /// Prints what would be downloaded as `id<TAB>format<TAB>reason<TAB>volume` and stops there
+        #[structopt(long)]
+        dry_run: bool,
+        #[structopt(flatten)]
+        output: OutputOptions,
+    },
+    /// Lists the pages kept from unfinished downloads as `id<TAB>pages<TAB>bytes`
+    Cache {
+        #[structopt(subcommand)]
+        command: Option<CacheCommand>,
+    },
+    /// Lists the download queue shared with the TUI as `id<TAB>state`
+    Queue {
+        #[structopt(subcommand)]
+        command: Option<QueueCommand>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum CacheCommand {
+    /// Deletes the cached pages of the given volumes, or of every volume
+    Purge { ids: Vec<u16> },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum QueueCommand {
+    /// Queues the volumes with the given ids, finished or failed ones are queued again
+    Add {
+        #[structopt(required = true)]
+        ids: Vec<u16>,
+    },
+    /// Takes the volumes with the given ids off the queue
+    Remove {
+        #[structopt(required = true)]
+        ids: Vec<u16>,
+    },
+    /// Takes the finished volumes off the queue
+    Clear,
+    /// Downloads every pending or failed volume in the queue
+    Run {
+        /// Saves queued chapters of a series that follow each other as one book
+        #[structopt(long)]
+        merge_chapters: bool,
+        #[structopt(flatten)]
+        output: OutputOptions,
+    },
+}
+
+// Where and how the downloads are saved, shared by `download`, `sync` and `queue run`.
+// Not a doc comment, structopt would use it to describe every subcommand it's flattened into
+#[derive(StructOpt, Debug)]
+pub struct OutputOptions {
+    /// Defaults to the destination selected in the TUI
+    #[structopt(short, long, parse(from_os_str))]
+    destination: Option<PathBuf>,
+    /// epub, cbz or images, repeat it to save several formats in one download.
+    /// Defaults to the format in the settings
+    #[structopt(long = "format")]
+    formats: Vec<OutputFormat>,
+    /// What to do with volumes that are already downloaded: skip, overwrite or keep-both.
+    /// Defaults to the policy in the settings
+    #[structopt(long)]
+    existing: Option<ExistingFiles>,
+}
+
+impl Options {
+    /// Settings from the config file and environment, with the flags on top
+    pub fn settings(&self) -> Result<Settings> {
+        let mut settings = Settings::load()?;
+
+        if let Some(api_url) = &self.api_url {
+            settings.api_url = api_url.clone();
+        }
+
+        Ok(settings)
+    }
+}
+
+impl Command {
+    pub async fn run(self, settings: Settings) -> Result<()> {
+        match self {
+            Command::Login { username, password } => login(&settings, username, password).await,
+            Command::List { sort } => list(&settings, sort).await,
+            Command::Missing => missing(&settings).await,
+            Command::History => history(),
+            Command::Download {
+                ids,
+                merge_chapters,
+                output,
+            } => {
+                let options = output.save_options(&settings).await?;
+                download(&settings, ids, merge_chapters, options).await
+            }
+            Command::Sync { dry_run, output } => {
+                let options = output.save_options(&settings).await?;
+                sync(&settings, options, dry_run).await
+            }
+            Command::Cache { command } => cache(command).await,
+            Command::Queue { command } => queue(&settings, command).await,
+        }
+    }
+}
+
+async fn login(settings: &Settings, username: String, password: Option<String>) -> Result<()> {
+    let password = match password {
+        Some(password) => password,
+        None => read_password()?,
+    };
+
+    let credentials = Credentials::write_user(username, password).await?;
+    credentials.fresh_login(settings.client()?).await?;
+
+    println!("Logged in");
+
+    Ok(())
+}
+
+/// From `K_DOWNLOAD_PASSWORD`, or the first line of stdin, asking for it on a terminal
+fn read_password() -> Result<String> {
+    if let Ok(password) = env::var(PASSWORD_VAR) {
+        return Ok(password);
+    }
+
+    let stdin = io::stdin();
+    let password = match stdin.is_terminal() {
+        true => prompt_password()?,
+        false => {
+            let mut line = String::new();
+            stdin.read_line(&mut line)?;
+
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+
+    if password.is_empty() {
+        bail!("No password, pass it on stdin or set {}", PASSWORD_VAR);
+    }
+
+    Ok(password)
+}
+
+/// Reads the password key by key with the terminal in raw mode, so it isn't echoed
+fn prompt_password() -> Result<String> {
+    eprint!("Password: ");
+    io::stderr().flush()?;
+
+    terminal::enable_raw_mode()?;
+    let password = read_keys();
+    terminal::disable_raw_mode()?;
+    eprintln!();
+
+    password
+}
+
+fn read_keys() -> Result<String> {
+    let mut password = String::new();
+
+    loop {
+        // Windows reports releases too, which would type every character twice
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind == KeyEventKind::Release {
+            continue;
+        }
+
+        match key.code {
+            KeyCode::Enter => return Ok(password),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                bail!("Cancelled")
+            }
+            KeyCode::Esc => bail!("Cancelled"),
+            KeyCode::Char(character) => password.push(character),
+            KeyCode::Backspace => {
+                password.pop();
+            }
+            _ => (),
+        }
+    }
+}
+
+async fn list(settings: &Settings, sort: Option<SortOrder>) -> Result<()> {
+    let manifest = Manifest::load()?;
+    let mut user = user(settings).await?;
+    let sort = sort.unwrap_or(settings.sort);
+
+    for comic in library(&mut user).await?.sorted(sort, &manifest) {
+        println!("{}\t{}\t{}", comic.id(), comic.series_name(), comic.name());
+    }
+
+    Ok(())
+}
+
+async fn missing(settings: &Settings) -> Result<()> {
+    let manifest = Manifest::load()?;
+    let mut user = user(settings).await?;
+
+    for comic in library(&mut user).await?.entries() {
+        if !manifest.is_downloaded(comic.id()) {
+            println!("{}\t{}\t{}", comic.id(), comic.series_name(), comic.name());
+        }
+    }
+
+    Ok(())
+}
+
+fn history() -> Result<()> {
+    for entry in Manifest::load()?.entries() {
+        println!(
+            "{}\t{}\t{}\t{}",
+            entry.volume_id,
+            entry.format.name(),
+            entry.downloaded_at.to_rfc3339(),
+            entry.path.display()
+        );
+    }
+
+    Ok(())
+}
+
+async fn download(
+    settings: &Settings,
+    ids: Vec<u16>,
+    merge_chapters: bool,
+    options: SaveOptions,
+) -> Result<()> {
+    let mut user = user(settings).await?;
+    let library = library(&mut user).await?;
+
+    if let Some(id) = ids.iter().find(|id| library.find(**id).is_none()) {
+        bail!("Volume {} isn't in your library", id);
+    }
+
+    let books = Book::all(library.books(&ids, merge_chapters), &options);
+    download_volumes(&mut user, books, options, None).await
+}
+
+async fn sync(settings: &Settings, options: SaveOptions, dry_run: bool) -> Result<()> {
+    let mut user = user(settings).await?;
+    let library = library(&mut user).await?;
+
+    let manifest = match &options.manifest {
+        Some(manifest) => manifest.clone(),
+        None => Manifest::load()?,
+    };
+    let actions = kodansha::sync::plan(&library.volumes, &manifest, &options);
+
+    if actions.is_empty() {
+        println!("Nothing to sync");
+        return Ok(());
+    }
+
+    if dry_run {
+        for action in actions {
+            let reason = match action.reason {
+                SyncReason::New => "new".to_string(),
+                SyncReason::Missing(path) => format!("missing from {}", path.display()),
+            };
+
+            println!(
+                "{}\t{}\t{}\t{}",
+                action.volume.id,
+                action.format.name(),
+                reason,
+                action.volume.volume_name
+            );
+        }
+
+        return Ok(());
+    }
+
+    // Queued first, so whatever an interrupted sync didn't get to is picked up by `queue run`
+    let mut queue = Queue::load()?;
+    let mut books: Vec<Book> = Vec::new();
+    let mut downloading = Vec::new();
+
+    // Only the formats that are missing, so the ones that are there aren't overwritten
+    for action in actions {
+        if let Some(book) = books
+            .iter_mut()
+            .find(|book| book.volume.id == action.volume.id)
+        {
+            book.formats.push(action.format);
+            continue;
+        }
+
+        if queue.state(action.volume.id) == Some(&QueueState::InProgress) {
+            if !downloading.contains(&action.volume.id) {
+                downloading.push(action.volume.id);
+                println!(
+                    "Skipped {}, it's already downloading",
+                    action.volume.volume_name
+                );
+            }
+            continue;
+        }
+
+        books.push(Book {
+            comics: vec![action.volume.id],
+            volume: action.volume,
+            formats: vec![action.format],
+        });
+    }
+    queue.update(|queue| {
+        for book in &books {
+            queue.add(book.volume.id);
+        }
+    })?;
+
+    if books.is_empty() {
+        return Ok(());
+    }
+
+    download_volumes(&mut user, books, options, Some(&mut queue)).await
+}
+
+async fn cache(command: Option<CacheCommand>) -> Result<()> {
+    let cache = PageCache::new()?;
+
+    match command {
+        None => {
+            for volume in cache.volumes().await? {
+                println!("{}\t{}\t{}", volume.id, volume.pages, volume.bytes);
+            }
+        }
+        Some(CacheCommand::Purge { ids }) if ids.is_empty() => {
+            cache.purge().await?;
+            println!("Purged the cache");
+        }
+        Some(CacheCommand::Purge { ids }) => {
+            for id in ids {
+                cache.remove(id).await?;
+                println!("Purged volume {}", id);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn queue(settings: &Settings, command: Option<QueueCommand>) -> Result<()> {
+    let mut queue = Queue::load()?;
+
+    match command {
+        None => {
+            for item in queue.items() {
+                println!("{}\t{}", item.id, item.state);
+            }
+        }
+        Some(QueueCommand::Add { ids }) => {
+            queue.update(|queue| {
+                for id in ids {
+                    queue.add(id);
+                }
+            })?;
+        }
+        Some(QueueCommand::Remove { ids }) => {
+            queue.update(|queue| {
+                for id in ids {
+                    queue.remove(id);
+                }
+            })?;
+        }
+        Some(QueueCommand::Clear) => {
+            queue.update(Queue::clear_done)?;
+        }
+        Some(QueueCommand::Run {
+            merge_chapters,
+            output,
+        }) => {
+            let options = output.save_options(settings).await?;
+            let mut user = user(settings).await?;
+            let library = library(&mut user).await?;
+
+            let ids = queue.update(|queue| {
+                let mut ids = Vec::new();
+                for id in queue.waiting() {
+                    match library.find(id) {
+                        Some(_) => ids.push(id),
+                        None => {
+                            if let Some(ticket) = queue.start(id) {
+                                queue.finish(ticket, Err("Not in your library".to_string()));
+                            }
+                        }
+                    }
+                }
+
+                ids
+            })?;
+
+            let books = Book::all(library.books(&ids, merge_chapters), &options);
+            if books.is_empty() {
+                println!("Nothing queued");
+                return Ok(());
+            }
+
+            download_volumes(&mut user, books, options, Some(&mut queue)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn user(settings: &Settings) -> Result<User> {
+    let credentials = Credentials::from_config()
+        .await
+        .map_err(|_| anyhow!("Not logged in, run `k-download login` first"))?;
+
+    Ok(credentials.login(settings.client()?).await?)
+}
+
+async fn library(user: &mut User) -> Result<Library> {
+    user.load_library().await?;
+
+    let library = user.library();
+    let library = library
+        .lock()
+        .map_err(|_| anyhow!("Couldn't read the mutex"))?;
+
+    Ok(library.clone().unwrap_or_default())
+}
+
+impl OutputOptions {
+    /// The flags, with the settings filling in whatever wasn't passed
+    async fn save_options(self, settings: &Settings) -> Result<SaveOptions> {
+        let destination = match self.destination {
+            Some(destination) => destination,
+            None => download_dir()
+                .await?
+                .ok_or(anyhow!("No destination, pass one with --destination"))?,
+        };
+        let formats = match self.formats.is_empty() {
+            true => vec![settings.format],
+            false => self.formats,
+        };
+
+        Ok(SaveOptions {
+            destination,
+            formats,
+            existing: self.existing.unwrap_or(settings.existing),
+            epub: settings.epub.clone(),
+            template: settings.path_template.clone(),
+            cache: Some(PageCache::new()?),
+            manifest: Some(Manifest::load()?),
+        })
+    }
+}
+
+/// A volume for [`download_volumes`] to save, made of `comics`, in `formats`
+struct Book {
+    volume: Volume,
+    comics: Vec<u16>,
+    formats: Vec<OutputFormat>,
+}
+
+impl Book {
+    /// The volumes, each made of the comics next to it, in every format of `options`
+    fn all(volumes: Vec<(Volume, Vec<u16>)>, options: &SaveOptions) -> Vec<Book> {
+        volumes
+            .into_iter()
+            .map(|(volume, comics)| Book {
+                volume,
+                comics,
+                formats: options.formats.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Downloads each book with `options`, in its own formats, and finishes them in the queue
+async fn download_volumes(
+    user: &mut User,
+    books: Vec<Book>,
+    options: SaveOptions,
+    mut queue: Option<&mut Queue>,
+) -> Result<()> {
+    let client = user.client().await?;
+    let (tx, mut rx) = channel(100);
+
+    // The progress is only interesting to the TUI, but the channel has to be drained
+    tokio::spawn(async move { while rx.recv().await.is_some() {} });
+
+    // Only bounds how many volumes download at once, the client paces the requests
+    let semaphore = Arc::new(Semaphore::new(3));
+    let mut set = JoinSet::new();
+    let total = books.len();
+
+    let mut tickets = HashMap::new();
+
+    for Book {
+        volume,
+        comics,
+        formats,
+    } in books
+    {
+        let options = SaveOptions {
+            formats,
+            ..options.clone()
+        };
+        let client = client.clone();
+        let tx = tx.clone();
+        let permit = semaphore.clone().acquire_owned().await?;
+
+        if let Some(queue) = queue.as_deref_mut() {
+            let started = queue.update(|queue| {
+                comics
+                    .iter()
+                    .filter_map(|id| queue.start(*id))
+                    .collect::<Vec<_>>()
+            })?;
+            tickets.insert(volume.id, started);
+        }
+
+        set.spawn(async move {
+            let result = volume.save_comics(&comics, &options, &client, tx).await;
+
+            drop(permit);
+
+            (volume, result)
+        });
+    }
+
+    let mut failed = 0;
+    while let Some(joined) = set.join_next().await {
+        let (volume, result) = joined?;
+
+        if let (Some(queue), Some(started)) = (queue.as_deref_mut(), tickets.remove(&volume.id)) {
+            let result = match &result {
+                Ok(saved) => Ok(Saved::all_skipped(saved)),
+                Err(err) => Err(err.to_string()),
+            };
+            queue.update(|queue| {
+                for ticket in started {
+                    match &result {
+                        Ok(Some(path)) => queue.skip(ticket, path.to_path_buf()),
+                        Ok(None) => queue.finish(ticket, Ok(())),
+                        Err(reason) => queue.finish(ticket, Err(reason.clone())),
+                    };
+                }
+            })?;
+        }
+
+        match (volume, result) {
+            (volume, Ok(saved)) => {
+                for saved in saved {
+                    match saved {
+                        Saved::Written(path) => {
+                            println!("Downloaded {} to {}", volume.volume_name, path.display())
+                        }
+                        Saved::Skipped(path) => println!(
+                            "Skipped {}, {} already exists",
+                            volume.volume_name,
+                            path.display()
+                        ),
+                    }
+                }
+            }
+            (volume, Err(err)) => {
+                failed += 1;
+                eprintln!("Failed to download {}: {}", volume.volume_name, err);
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} of {} volumes failed to download", failed, total);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::path::PathBuf;
+
+use tokio::fs;
+
+use super::error::Result;
+use super::user::data_dir;
+use super::ImageType;
+
+const CACHE_DIR: &str = "cache";
+
+/// Pages that were already downloaded, kept in the data dir by volume id and
+/// page number, so an interrupted volume only fetches the pages it's missing
+#[derive(Clone, Debug)]
+pub struct PageCache {
+    dir: PathBuf,
+}
+
+/// What the cache holds for one volume
+#[derive(Debug, Clone)]
+pub struct CachedVolume {
+    pub id: u16,
+    pub pages: usize,
+    pub bytes: u64,
+}
+
+impl PageCache {
+    pub fn new() -> Result<PageCache> {
+        let mut dir = data_dir()?;
+        dir.push(CACHE_DIR);
+
+        Ok(PageCache { dir })
+    }
+
+    fn volume_dir(&self, volume: u16) -> PathBuf {
+        self.dir.join(volume.to_string())
+    }
+
+    pub async fn get(&self, volume: u16, page: usize) -> Option<(ImageType, Box<[u8]>)> {
+        let volume_dir = self.volume_dir(volume);
+
+        for image_type in ImageType::ALL {
+            let path = volume_dir.join(format!("{}.{}", page, image_type.extension()));
+
+            if let Ok(bytes) = fs::read(&path).await {
+                return Some((image_type, bytes.into()));
+            }
+        }
+
+        None
+    }
+
+    pub async fn put(
+        &self,
+        volume: u16,
+        page: usize,
+        image_type: ImageType,
+        bytes: &[u8],
+    ) -> Result<()> {
+        let volume_dir = self.volume_dir(volume);
+        fs::create_dir_all(&volume_dir).await?;
+
+        // Written aside first, so a page cut off by quitting is never mistaken for a whole one
+        let path = volume_dir.join(format!("{}.{}", page, image_type.extension()));
+        let partial = volume_dir.join(format!("{}.part", page));
+        fs::write(&partial, bytes).await?;
+        fs::rename(&partial, &path).await?;
+
+        Ok(())
+    }
+
+    /// Forgets a volume, once it's saved its pages aren't needed anymore
+    pub async fn remove(&self, volume: u16) -> Result<()> {
+        match fs::remove_dir_all(self.volume_dir(volume)).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn volumes(&self) -> Result<Vec<CachedVolume>> {
+        let mut volumes = Vec::new();
+
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(volumes),
+            Err(err) => return Err(err.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let id = match entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            {
+                Some(id) => id,
+                None => continue,
+            };
+
+            let mut volume = CachedVolume {
+                id,
+                pages: 0,
+                bytes: 0,
+            };
+
+            let mut pages = fs::read_dir(entry.path()).await?;
+            while let Some(page) = pages.next_entry().await? {
+                volume.pages += 1;
+                volume.bytes += page.metadata().await?.len();
+            }
+
+            volumes.push(volume);
+        }
+
+        volumes.sort_by_key(|volume| volume.id);
+
+        Ok(volumes)
+    }
+
+    pub async fn purge(&self) -> Result<()> {
+        match fs::remove_dir_all(&self.dir).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
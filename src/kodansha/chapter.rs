@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+
+use super::Volume;
+
+/// A simulpub chapter, from the same `mycomics/` list as the volumes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Chapter {
+    pub series_name: String,
+    /// Chapters like 10.5 exist, and not every chapter comes with a number
+    #[serde(default)]
+    pub chapter_number: Option<f32>,
+    /// The volume the chapter will be collected in, when it's known
+    #[serde(default)]
+    pub volume_number: u8,
+    pub page_count: u16,
+    #[serde(default)]
+    pub description: String,
+    pub id: u16,
+    pub series_id: u16,
+}
+
+impl Chapter {
+    /// Like "Chapter 12", without the series
+    pub fn name(&self) -> String {
+        match self.chapter_number {
+            Some(number) => format!("Chapter {}", number),
+            None => format!("Chapter #{}", self.id),
+        }
+    }
+
+    /// The chapter as a book of its own, which is how it's saved
+    pub fn as_volume(&self) -> Volume {
+        Volume {
+            series_name: self.series_name.clone(),
+            volume_name: format!("{} {}", self.series_name, self.name()),
+            volume_number: self.volume_number,
+            page_count: self.page_count,
+            description: self.description.clone(),
+            id: self.id,
+            series_id: self.series_id,
+        }
+    }
+
+    /// One book out of a run of chapters of the same series, saved with
+    /// [`Volume::save_comics`] and the chapters' ids. Named after the first and
+    /// last chapter, and its EPUB identifier comes from their ids. It keeps the
+    /// first chapter's id for progress and the cache. A single chapter is saved
+    /// as it is.
+    pub fn merge(chapters: &[Chapter]) -> Option<Volume> {
+        let (first, last) = (chapters.first()?, chapters.last()?);
+        if chapters.len() == 1 {
+            return Some(first.as_volume());
+        }
+
+        let numbers = |chapter: &Chapter| match chapter.chapter_number {
+            Some(number) => number.to_string(),
+            None => format!("#{}", chapter.id),
+        };
+
+        Some(Volume {
+            series_name: first.series_name.clone(),
+            volume_name: format!(
+                "{} Chapters {}-{}",
+                first.series_name,
+                numbers(first),
+                numbers(last)
+            ),
+            volume_number: first.volume_number,
+            page_count: chapters.iter().map(|chapter| chapter.page_count).sum(),
+            description: first.description.clone(),
+            id: first.id,
+            series_id: first.series_id,
+        })
+    }
+
+    /// Whether `next` is the chapter after this one, counting a 10.5 between 10 and 11
+    pub(crate) fn is_followed_by(&self, next: &Chapter) -> bool {
+        if self.series_id != next.series_id {
+            return false;
+        }
+
+        match (self.chapter_number, next.chapter_number) {
+            (Some(number), Some(next)) => next - number <= 1.0,
+            _ => true,
+        }
+    }
+
+    /// Reading order within a series
+    pub(crate) fn order(&self) -> (f32, u16) {
+        (self.chapter_number.unwrap_or(f32::MAX), self.id)
+    }
+}
+
+/// Anything in the library that can be downloaded
+#[derive(Debug, Clone)]
+pub enum Comic {
+    Volume(Volume),
+    Chapter(Chapter),
+}
+
+impl Comic {
+    pub fn id(&self) -> u16 {
+        match self {
+            Comic::Volume(volume) => volume.id,
+            Comic::Chapter(chapter) => chapter.id,
+        }
+    }
+
+    pub fn series_id(&self) -> u16 {
+        match self {
+            Comic::Volume(volume) => volume.series_id,
+            Comic::Chapter(chapter) => chapter.series_id,
+        }
+    }
+
+    pub fn series_name(&self) -> &str {
+        match self {
+            Comic::Volume(volume) => &volume.series_name,
+            Comic::Chapter(chapter) => &chapter.series_name,
+        }
+    }
+
+    /// The volume's name, or the chapter's with its series
+    pub fn name(&self) -> String {
+        match self {
+            Comic::Volume(volume) => volume.volume_name.clone(),
+            Comic::Chapter(chapter) => format!("{} {}", chapter.series_name, chapter.name()),
+        }
+    }
+
+    pub fn description(&self) -> &str {
+        match self {
+            Comic::Volume(volume) => &volume.description,
+            Comic::Chapter(chapter) => &chapter.description,
+        }
+    }
+
+    pub fn page_count(&self) -> u16 {
+        match self {
+            Comic::Volume(volume) => volume.page_count,
+            Comic::Chapter(chapter) => chapter.page_count,
+        }
+    }
+
+    /// The volume or chapter number, chapters without one come last
+    pub fn number(&self) -> f32 {
+        match self {
+            Comic::Volume(volume) => volume.volume_number as f32,
+            Comic::Chapter(chapter) => chapter.order().0,
+        }
+    }
+
+    pub fn is_chapter(&self) -> bool {
+        matches!(self, Comic::Chapter(_))
+    }
+
+    /// What's saved when the comic is downloaded on its own
+    pub fn as_volume(&self) -> Volume {
+        match self {
+            Comic::Volume(volume) => volume.clone(),
+            Comic::Chapter(chapter) => chapter.as_volume(),
+        }
+    }
+}
@@ -0,0 +1,43 @@
+/// A setting that's one of a few named options, like the output format.
+///
+/// Each is toggled through in the TUI and parsed from its name on the command
+/// line, its `Display` is what the TUI shows.
+pub trait Choice: Sized + Copy + PartialEq + 'static {
+    /// Every option, in the order the TUI toggles through them
+    const ALL: &'static [Self];
+    /// What an option is called in errors, like "format"
+    const KIND: &'static str;
+
+    /// The name used in the settings and on the command line
+    fn name(&self) -> &'static str;
+
+    /// The option after this one, for toggling through them in the TUI
+    fn next(&self) -> Self {
+        let index = Self::ALL
+            .iter()
+            .position(|option| option == self)
+            .unwrap_or_default();
+
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    /// The option with this name, ignoring case, for `FromStr`
+    fn parse(s: &str) -> Result<Self, String> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|option| option.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<&str> = Self::ALL.iter().map(Self::name).collect();
+                let (last, rest) = names.split_last().unwrap_or((&"", &[]));
+
+                format!(
+                    "Unknown {} {}, expected one of {} or {}",
+                    Self::KIND,
+                    s,
+                    rest.join(", "),
+                    last
+                )
+            })
+    }
+}
@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use reqwest::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    Client, Proxy, RequestBuilder, Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{
+    error::Result, DownloadControl, Endpoint, KodanshaError, RateLimiter, RetryPolicy, Settings,
+};
+
+const USER_AGENT: &str = concat!("k-download/", env!("CARGO_PKG_VERSION"));
+
+/// A pooled http client that every request to Kodansha goes through.
+///
+/// Cloning is cheap and clones share the same connection pool and rate limiter,
+/// so a clone with a token can be handed to every task of a download.
+#[derive(Clone, Debug)]
+pub struct KodanshaClient {
+    http: Client,
+    endpoint: Endpoint,
+    token: Option<String>,
+    retry: RetryPolicy,
+    limiter: RateLimiter,
+    control: Option<DownloadControl>,
+}
+
+impl KodanshaClient {
+    pub fn new(settings: &Settings) -> Result<KodanshaClient> {
+        let mut builder = Client::builder()
+            .user_agent(USER_AGENT)
+            .connect_timeout(Duration::from_secs(settings.connect_timeout_secs))
+            .timeout(Duration::from_secs(settings.timeout_secs));
+
+        if let Some(proxy) = &settings.proxy {
+            let proxy = Proxy::all(proxy).map_err(|err| KodanshaError::Config(err.to_string()))?;
+            builder = builder.proxy(proxy);
+        }
+
+        Ok(KodanshaClient {
+            http: builder.build()?,
+            endpoint: settings.endpoint(),
+            token: None,
+            retry: settings.retry.clone(),
+            limiter: RateLimiter::new(settings.rate_limit.clone()),
+            control: None,
+        })
+    }
+
+    /// A clone of the client that sends the bearer token with every request
+    pub fn authorized(&self, token: String) -> KodanshaClient {
+        KodanshaClient {
+            http: self.http.clone(),
+            endpoint: self.endpoint.clone(),
+            token: Some(token),
+            retry: self.retry.clone(),
+            limiter: self.limiter.clone(),
+            control: self.control.clone(),
+        }
+    }
+
+    /// A clone of the client that holds every request while the download is paused
+    pub fn controlled(&self, control: DownloadControl) -> KodanshaClient {
+        KodanshaClient {
+            control: Some(control),
+            ..self.clone()
+        }
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    pub fn retry(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    pub async fn get_json<T>(&self, route: &str) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let request = self.auth(self.http.get(self.endpoint.url(route)));
+
+        Ok(self.send(request).await?.json::<T>().await?)
+    }
+
+    pub async fn post_json<B, T>(&self, route: &str, body: &B) -> Result<T>
+    where
+        B: Serialize,
+        T: DeserializeOwned,
+    {
+        let request = self.auth(self.http.post(self.endpoint.url(route)).json(body));
+
+        Ok(self.send(request).await?.json::<T>().await?)
+    }
+
+    /// Gets an url handed out by the API, which may live outside the endpoint.
+    ///
+    /// Returns the body along with its `Content-Type`, if the server sent one.
+    pub async fn get_bytes(&self, url: &str) -> Result<(Option<String>, Box<[u8]>)> {
+        let request = self.auth(self.http.get(self.endpoint.resolve(url)));
+        let response = self.send(request).await?;
+
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        Ok((content_type, response.bytes().await?.as_ref().into()))
+    }
+
+    /// Sends the request and turns error statuses into a [`KodanshaError`]
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        if let Some(control) = &self.control {
+            control.unpaused().await;
+        }
+        self.limiter.acquire().await;
+
+        let response = request.send().await?;
+        let status = response.status();
+
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        Err(match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => match self.token {
+                Some(_) => KodanshaError::TokenExpired,
+                None => KodanshaError::AuthFailed,
+            },
+            StatusCode::NOT_FOUND => KodanshaError::NotFound(response.url().to_string()),
+            StatusCode::TOO_MANY_REQUESTS => KodanshaError::RateLimited {
+                retry_after: retry_after(&response),
+            },
+            status if status.is_server_error() => KodanshaError::Server(status.as_u16()),
+            status => {
+                KodanshaError::UnexpectedPayload(format!("{} from {}", status, response.url()))
+            }
+        })
+    }
+
+    fn auth(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+/// Only the delay in seconds form of `Retry-After` is supported, as that's what Kodansha sends
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+impl Default for KodanshaClient {
+    fn default() -> Self {
+        KodanshaClient {
+            http: Client::builder()
+                .user_agent(USER_AGENT)
+                .build()
+                .unwrap_or_default(),
+            endpoint: Endpoint::default(),
+            token: None,
+            retry: RetryPolicy::default(),
+            limiter: RateLimiter::default(),
+            control: None,
+        }
+    }
+}
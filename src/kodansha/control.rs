@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+/// Pauses, resumes and cancels one download from outside of it.
+///
+/// Clones control the same download. A client made with
+/// [`KodanshaClient::controlled`](super::KodanshaClient::controlled) holds its
+/// requests while paused, cancelling is up to whoever runs the download, by
+/// racing it against [`DownloadControl::cancelled`].
+#[derive(Clone, Debug)]
+pub struct DownloadControl {
+    cancel: CancellationToken,
+    paused: Arc<watch::Sender<bool>>,
+}
+
+impl DownloadControl {
+    pub fn new() -> DownloadControl {
+        let (paused, _) = watch::channel(false);
+
+        DownloadControl {
+            cancel: CancellationToken::new(),
+            paused: Arc::new(paused),
+        }
+    }
+
+    /// Requests already sent still finish, new ones wait for [`DownloadControl::resume`]
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Completes once the download is cancelled
+    pub async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+
+    /// Completes right away, unless the download is paused
+    pub async fn unpaused(&self) {
+        let mut paused = self.paused.subscribe();
+
+        while *paused.borrow_and_update() {
+            // Only fails once the sender is dropped, and self holds on to it
+            if paused.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for DownloadControl {
+    fn default() -> Self {
+        DownloadControl::new()
+    }
+}
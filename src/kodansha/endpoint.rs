@@ -0,0 +1,36 @@
+pub const DEFAULT_API_URL: &str = "https://api.kodansha.us";
+
+/// Where the Kodansha API lives, so it can be swapped for a local stand-in
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint {
+    base: String,
+}
+
+impl Endpoint {
+    pub fn new(base: impl Into<String>) -> Endpoint {
+        let base: String = base.into();
+
+        Endpoint {
+            base: base.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn url(&self, route: &str) -> String {
+        format!("{}/{}", self.base, route.trim_start_matches('/'))
+    }
+
+    /// Absolute urls handed out by the API are kept as is, relative ones are joined onto the base
+    pub fn resolve(&self, url: &str) -> String {
+        if url.starts_with("http://") || url.starts_with("https://") {
+            url.to_string()
+        } else {
+            self.url(url)
+        }
+    }
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Endpoint::new(DEFAULT_API_URL)
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, KodanshaError>;
+
+#[derive(Error, Debug)]
+pub enum KodanshaError {
+    #[error("Login failed, check your username and password")]
+    AuthFailed,
+
+    #[error("Your session has expired, please log in again")]
+    TokenExpired,
+
+    #[error("Kodansha is rate limiting requests{}", retry_after_text(.retry_after))]
+    RateLimited { retry_after: Option<Duration> },
+
+    #[error("Couldn't find {0}")]
+    NotFound(String),
+
+    #[error("Unexpected response from Kodansha: {0}")]
+    UnexpectedPayload(String),
+
+    #[error("Kodansha had a problem, it answered {0}")]
+    Server(u16),
+
+    #[error("Network error: {0}")]
+    Network(reqwest::Error),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Configuration error: {0}")]
+    Config(String),
+
+    #[error("Couldn't build the book: {0}")]
+    Epub(String),
+}
+
+fn retry_after_text(retry_after: &Option<Duration>) -> String {
+    match retry_after {
+        Some(duration) => format!(", retry in {} seconds", duration.as_secs()),
+        None => String::new(),
+    }
+}
+
+impl From<reqwest::Error> for KodanshaError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            KodanshaError::UnexpectedPayload(err.to_string())
+        } else {
+            KodanshaError::Network(err)
+        }
+    }
+}
+
+impl From<toml::de::Error> for KodanshaError {
+    fn from(err: toml::de::Error) -> Self {
+        KodanshaError::Config(err.to_string())
+    }
+}
+
+impl From<toml::ser::Error> for KodanshaError {
+    fn from(err: toml::ser::Error) -> Self {
+        KodanshaError::Config(err.to_string())
+    }
+}
+
+impl From<zip::result::ZipError> for KodanshaError {
+    fn from(err: zip::result::ZipError) -> Self {
+        match err {
+            zip::result::ZipError::Io(err) => KodanshaError::Io(err),
+            err => KodanshaError::Epub(err.to_string()),
+        }
+    }
+}
+
+impl From<config::ConfigError> for KodanshaError {
+    fn from(err: config::ConfigError) -> Self {
+        KodanshaError::Config(err.to_string())
+    }
+}
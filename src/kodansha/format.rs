@@ -0,0 +1,79 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use super::choice::Choice;
+use super::error::Result;
+use super::output::partial_path;
+use super::sink::{CbzSink, EpubOptions, EpubSink, ImageFolderSink, VolumeSink};
+
+/// The kinds of files a volume can be saved as
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Epub,
+    Cbz,
+    /// The original images in a folder, for repackaging later
+    Images,
+}
+
+impl Choice for OutputFormat {
+    const ALL: &'static [OutputFormat] =
+        &[OutputFormat::Epub, OutputFormat::Cbz, OutputFormat::Images];
+    const KIND: &'static str = "format";
+
+    fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Epub => "epub",
+            OutputFormat::Cbz => "cbz",
+            OutputFormat::Images => "images",
+        }
+    }
+}
+
+impl OutputFormat {
+    /// None for formats that are saved as a folder
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            OutputFormat::Epub => Some("epub"),
+            OutputFormat::Cbz => Some("cbz"),
+            OutputFormat::Images => None,
+        }
+    }
+
+    /// A sink writing this format to the [`partial_path`] of `path`
+    pub fn sink(&self, path: &Path, epub: &EpubOptions) -> Result<Box<dyn VolumeSink>> {
+        Ok(match self {
+            OutputFormat::Epub => Box::new(EpubSink::new(partial_file(path)?, epub.clone())?),
+            OutputFormat::Cbz => Box::new(CbzSink::new(partial_file(path)?)),
+            OutputFormat::Images => Box::new(ImageFolderSink::new(partial_path(path))),
+        })
+    }
+}
+
+fn partial_file(path: &Path) -> Result<BufWriter<File>> {
+    // Templates can put volumes in folders that don't exist yet
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    Ok(BufWriter::new(File::create(partial_path(path))?))
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name().to_uppercase())
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        OutputFormat::parse(s)
+    }
+}
@@ -0,0 +1,169 @@
+/// The image formats Kodansha serves pages in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageType {
+    #[default]
+    Jpeg,
+    Png,
+    Webp,
+    Gif,
+}
+
+impl ImageType {
+    pub const ALL: [ImageType; 4] = [
+        ImageType::Jpeg,
+        ImageType::Png,
+        ImageType::Webp,
+        ImageType::Gif,
+    ];
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ImageType::Jpeg => "image/jpeg",
+            ImageType::Png => "image/png",
+            ImageType::Webp => "image/webp",
+            ImageType::Gif => "image/gif",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageType::Jpeg => "jpeg",
+            ImageType::Png => "png",
+            ImageType::Webp => "webp",
+            ImageType::Gif => "gif",
+        }
+    }
+
+    /// Parses a media type, ignoring parameters like `; charset=binary`
+    pub fn from_mime(mime: &str) -> Option<ImageType> {
+        let mime = mime.split(';').next()?.trim();
+
+        match mime.to_ascii_lowercase().as_str() {
+            "image/jpg" | "image/pjpeg" => Some(ImageType::Jpeg),
+            mime => ImageType::ALL
+                .into_iter()
+                .find(|image_type| image_type.mime() == mime),
+        }
+    }
+
+    /// Recognises the image from its magic bytes
+    pub fn from_bytes(bytes: &[u8]) -> Option<ImageType> {
+        match bytes {
+            [0xFF, 0xD8, 0xFF, ..] => Some(ImageType::Jpeg),
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n', ..] => Some(ImageType::Png),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => {
+                Some(ImageType::Webp)
+            }
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(ImageType::Gif),
+            _ => None,
+        }
+    }
+
+    /// The type of a downloaded page, trusting the bytes over the `Content-Type`.
+    ///
+    /// Error pages served with a 200 are HTML or JSON, which is never an image.
+    pub fn sniff(content_type: Option<&str>, bytes: &[u8]) -> Option<ImageType> {
+        if let Some(image_type) = ImageType::from_bytes(bytes) {
+            return Some(image_type);
+        }
+
+        // Empty, or the start of markup or JSON
+        let text = match bytes.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(byte) => matches!(byte, b'<' | b'{' | b'['),
+            None => true,
+        };
+
+        if text {
+            return None;
+        }
+
+        content_type.and_then(ImageType::from_mime)
+    }
+
+    /// Width and height in pixels, read from the image's header
+    pub fn dimensions(&self, bytes: &[u8]) -> Option<(u32, u32)> {
+        match self {
+            ImageType::Jpeg => jpeg_dimensions(bytes),
+            ImageType::Png => {
+                let header = bytes.get(16..24)?;
+
+                Some((be_u32(&header[..4]), be_u32(&header[4..])))
+            }
+            ImageType::Webp => webp_dimensions(bytes),
+            ImageType::Gif => {
+                let header = bytes.get(6..10)?;
+
+                Some((le_u16(&header[..2]) as u32, le_u16(&header[2..]) as u32))
+            }
+        }
+    }
+}
+
+/// Walks the segments until the start of frame, which holds the size
+fn jpeg_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let mut offset = 2;
+
+    loop {
+        let marker = *bytes.get(offset + 1)?;
+        if bytes[offset] != 0xFF {
+            return None;
+        }
+
+        // Padding between segments
+        if marker == 0xFF {
+            offset += 1;
+            continue;
+        }
+
+        let length = be_u16(bytes.get(offset + 2..offset + 4)?) as usize;
+
+        // SOF0 to SOF15, apart from DHT, JPG and DAC which share the range
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            let frame = bytes.get(offset + 5..offset + 9)?;
+
+            return Some((be_u16(&frame[2..]) as u32, be_u16(&frame[..2]) as u32));
+        }
+
+        offset += 2 + length;
+    }
+}
+
+/// The size lives in a different place for lossy, lossless and extended WebP
+fn webp_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    match bytes.get(12..16)? {
+        b"VP8 " => {
+            let frame = bytes.get(26..30)?;
+
+            Some((
+                (le_u16(&frame[..2]) & 0x3FFF) as u32,
+                (le_u16(&frame[2..]) & 0x3FFF) as u32,
+            ))
+        }
+        b"VP8L" => {
+            let bits = bytes.get(21..25)?;
+            let bits = u32::from_le_bytes([bits[0], bits[1], bits[2], bits[3]]);
+
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+        }
+        b"VP8X" => {
+            let canvas = bytes.get(24..30)?;
+            let width = u32::from_le_bytes([canvas[0], canvas[1], canvas[2], 0]);
+            let height = u32::from_le_bytes([canvas[3], canvas[4], canvas[5], 0]);
+
+            Some((width + 1, height + 1))
+        }
+        _ => None,
+    }
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn le_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
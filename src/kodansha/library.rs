@@ -1,8 +1,227 @@
-use serde::Deserialize;
+use std::cmp::Ordering;
+use std::{fmt, str::FromStr};
 
+use serde::{Deserialize, Serialize};
+
+use super::{Chapter, Choice, Comic, Manifest};
 use crate::Volume;
 
 #[derive(Deserialize, Default, Clone)]
 pub struct Library {
     pub volumes: Vec<Volume>,
+    /// Sorted by series, then in reading order
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+    /// Every id in the order `mycomics/` lists them. It isn't documented what
+    /// that order is, so it's only called the API order
+    #[serde(default)]
+    pub added: Vec<u16>,
+}
+
+/// How the library is listed, set with `sort` in the settings
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortOrder {
+    /// API order, the order `mycomics/` lists them in. It's called `added`, but
+    /// nothing says that's when they were added
+    #[default]
+    Added,
+    /// By series name, then in reading order
+    Series,
+    /// By volume or chapter number, across series
+    Number,
+    /// The longest first
+    Pages,
+    /// What wasn't downloaded yet first
+    Downloaded,
+}
+
+impl Library {
+    /// Everything in the library, each series' volumes followed by its chapters
+    pub fn entries(&self) -> Vec<Comic> {
+        let mut entries: Vec<Comic> = Vec::new();
+
+        for volume in &self.volumes {
+            if entries
+                .iter()
+                .any(|entry| entry.series_id() == volume.series_id)
+            {
+                continue;
+            }
+
+            entries.extend(
+                self.volumes
+                    .iter()
+                    .filter(|other| other.series_id == volume.series_id)
+                    .cloned()
+                    .map(Comic::Volume),
+            );
+            entries.extend(self.series_chapters(volume.series_id));
+        }
+
+        // Series where only chapters are out
+        for chapter in &self.chapters {
+            if !entries
+                .iter()
+                .any(|entry| entry.series_id() == chapter.series_id)
+            {
+                entries.extend(self.series_chapters(chapter.series_id));
+            }
+        }
+
+        entries
+    }
+
+    fn series_chapters(&self, series_id: u16) -> impl Iterator<Item = Comic> + '_ {
+        self.chapters
+            .iter()
+            .filter(move |chapter| chapter.series_id == series_id)
+            .cloned()
+            .map(Comic::Chapter)
+    }
+
+    /// Like [`Library::entries`], in `sort` order. Ties keep the order of the entries.
+    pub fn sorted(&self, sort: SortOrder, manifest: &Manifest) -> Vec<Comic> {
+        let mut entries = self.entries();
+
+        let by_series = |a: &Comic, b: &Comic| {
+            a.series_name()
+                .to_lowercase()
+                .cmp(&b.series_name().to_lowercase())
+        };
+        let by_number = |a: &Comic, b: &Comic| {
+            a.number()
+                .partial_cmp(&b.number())
+                .unwrap_or(Ordering::Equal)
+        };
+
+        match sort {
+            SortOrder::Added => entries.sort_by_key(|comic| {
+                self.added
+                    .iter()
+                    .position(|id| *id == comic.id())
+                    .unwrap_or(usize::MAX)
+            }),
+            // Volumes before chapters, a volume collects chapters that came out before it
+            SortOrder::Series => entries.sort_by(|a, b| {
+                by_series(a, b)
+                    .then(a.is_chapter().cmp(&b.is_chapter()))
+                    .then(by_number(a, b))
+            }),
+            SortOrder::Number => entries.sort_by(|a, b| by_number(a, b).then(by_series(a, b))),
+            SortOrder::Pages => entries.sort_by_key(|comic| std::cmp::Reverse(comic.page_count())),
+            SortOrder::Downloaded => {
+                entries.sort_by_key(|comic| manifest.is_downloaded(comic.id()))
+            }
+        }
+
+        entries
+    }
+
+    /// The volume or chapter with this id
+    pub fn find(&self, id: u16) -> Option<Comic> {
+        let volume = self.volumes.iter().find(|volume| volume.id == id);
+        if let Some(volume) = volume {
+            return Some(Comic::Volume(volume.clone()));
+        }
+
+        let chapter = self.chapters.iter().find(|chapter| chapter.id == id)?;
+        Some(Comic::Chapter(chapter.clone()))
+    }
+
+    /// The chapters among `ids` in runs of chapters that follow each other in a
+    /// series, to be merged into one book each. Ids that aren't chapters are left out.
+    pub fn chapter_runs(&self, ids: &[u16]) -> Vec<Vec<Chapter>> {
+        let mut runs: Vec<Vec<Chapter>> = Vec::new();
+        let mut previous: Option<&Chapter> = None;
+
+        // Chapters are in reading order already, so a gap is a chapter that isn't picked,
+        // or one that isn't in the library at all
+        for chapter in &self.chapters {
+            if !ids.contains(&chapter.id) {
+                previous = None;
+                continue;
+            }
+
+            match (previous, runs.last_mut()) {
+                (Some(previous), Some(run)) if previous.is_followed_by(chapter) => {
+                    run.push(chapter.clone())
+                }
+                _ => runs.push(vec![chapter.clone()]),
+            }
+
+            previous = Some(chapter);
+        }
+
+        runs
+    }
+
+    /// What downloading `ids` saves, each book with the ids of the comics in it.
+    ///
+    /// Volumes and chapters are books of their own, unless `merge_chapters` puts
+    /// every run of chapters in one. Ids that aren't in the library are left out.
+    pub fn books(&self, ids: &[u16], merge_chapters: bool) -> Vec<(Volume, Vec<u16>)> {
+        let mut books = Vec::new();
+
+        for id in ids {
+            match self.find(*id) {
+                Some(Comic::Chapter(_)) if merge_chapters => (),
+                Some(comic) => books.push((comic.as_volume(), vec![*id])),
+                None => (),
+            }
+        }
+
+        if merge_chapters {
+            for run in self.chapter_runs(ids) {
+                if let Some(volume) = Chapter::merge(&run) {
+                    books.push((volume, run.iter().map(|chapter| chapter.id).collect()));
+                }
+            }
+        }
+
+        books
+    }
+}
+
+impl Choice for SortOrder {
+    const ALL: &'static [SortOrder] = &[
+        SortOrder::Added,
+        SortOrder::Series,
+        SortOrder::Number,
+        SortOrder::Pages,
+        SortOrder::Downloaded,
+    ];
+    const KIND: &'static str = "sort";
+
+    fn name(&self) -> &'static str {
+        match self {
+            SortOrder::Added => "added",
+            SortOrder::Series => "series",
+            SortOrder::Number => "number",
+            SortOrder::Pages => "pages",
+            SortOrder::Downloaded => "downloaded",
+        }
+    }
+}
+
+impl SortOrder {
+    /// Whether the TUI lists the library under its series, in the order each
+    /// series first shows up. The other orders would only reorder the series.
+    pub fn groups_series(&self) -> bool {
+        matches!(self, SortOrder::Added | SortOrder::Series)
+    }
+}
+
+impl fmt::Display for SortOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        SortOrder::parse(s)
+    }
 }
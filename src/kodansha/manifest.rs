@@ -0,0 +1,283 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::error::Result;
+use super::queue::lock;
+use super::user::data_dir;
+use super::{KodanshaError, OutputFormat, Volume};
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// One format of a volume that was saved
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub volume_id: u16,
+    pub series_id: u16,
+    pub path: PathBuf,
+    pub format: OutputFormat,
+    /// For image folders, everything in the folder
+    pub bytes: u64,
+    pub page_count: usize,
+    /// Hex encoded SHA-256 of the file, or of a folder's files in name order
+    pub hash: String,
+    pub downloaded_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ManifestFile {
+    entries: Vec<ManifestEntry>,
+}
+
+/// Every volume that was downloaded, kept in the data dir so the TUI can mark
+/// them and the CLI can tell what's missing.
+///
+/// Clones share the entries, so one manifest can be handed to every download.
+/// The TUI and CLI can download at the same time, so entries are recorded into
+/// what's on disk under a lock file, like the queue.
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    path: PathBuf,
+    entries: Arc<Mutex<Vec<ManifestEntry>>>,
+    /// When the file was last read, so refreshing skips reading it again when it didn't change
+    read_at: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl ManifestEntry {
+    /// Describes the volume just saved to `path`, reading it back for its size and hash
+    pub async fn new(
+        volume: &Volume,
+        format: OutputFormat,
+        path: &Path,
+        page_count: usize,
+    ) -> Result<ManifestEntry> {
+        let measured = path.to_path_buf();
+        let (bytes, hash) = tokio::task::spawn_blocking(move || measure(&measured))
+            .await
+            .map_err(io::Error::other)??;
+
+        Ok(ManifestEntry {
+            volume_id: volume.id,
+            series_id: volume.series_id,
+            path: path.to_path_buf(),
+            format,
+            bytes,
+            page_count,
+            hash,
+            downloaded_at: Utc::now(),
+        })
+    }
+}
+
+impl Manifest {
+    /// Loading and recording are synchronous, like the queue, so concurrent
+    /// downloads write the file one at a time
+    pub fn load() -> Result<Manifest> {
+        let mut path = data_dir()?;
+        path.push(MANIFEST_FILE);
+
+        Manifest::at(path)
+    }
+
+    fn at(path: PathBuf) -> Result<Manifest> {
+        let manifest = Manifest {
+            path,
+            entries: Arc::default(),
+            read_at: Arc::default(),
+        };
+        manifest.refresh()?;
+
+        Ok(manifest)
+    }
+
+    /// Picks up the entries other processes recorded since the file was last read
+    pub fn refresh(&self) -> Result<()> {
+        let modified = modified(&self.path);
+        let mut read_at = self
+            .read_at
+            .lock()
+            .map_err(|_| KodanshaError::Config("Couldn't lock the manifest".to_string()))?;
+        if modified.is_some() && *read_at == modified {
+            return Ok(());
+        }
+
+        let entries = read(&self.path)?;
+        *self
+            .entries
+            .lock()
+            .map_err(|_| KodanshaError::Config("Couldn't lock the manifest".to_string()))? =
+            entries;
+        *read_at = modified;
+
+        Ok(())
+    }
+
+    /// Adds the entry, replacing whatever was recorded for the same volume and path before
+    pub fn record(&self, entry: ManifestEntry) -> Result<()> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| KodanshaError::Config("Couldn't lock the manifest".to_string()))?;
+
+        let _lock = lock(&self.path)?;
+        *entries = read(&self.path)?;
+
+        entries.retain(|recorded| {
+            recorded.path != entry.path || recorded.volume_id != entry.volume_id
+        });
+        entries.push(entry);
+
+        let file = ManifestFile {
+            entries: entries.clone(),
+        };
+        let json = serde_json::to_string_pretty(&file)
+            .map_err(|err| KodanshaError::Config(err.to_string()))?;
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut partial = self.path.clone();
+        partial.set_extension("json.part");
+        fs::write(&partial, json)?;
+        fs::rename(partial, &self.path)?;
+
+        Ok(())
+    }
+
+    pub fn entries(&self) -> Vec<ManifestEntry> {
+        self.entries
+            .lock()
+            .map(|entries| entries.clone())
+            .unwrap_or_default()
+    }
+
+    /// Whether the volume was recorded at exactly this path
+    pub fn contains(&self, volume_id: u16, path: &Path) -> bool {
+        self.entries
+            .lock()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .any(|entry| entry.volume_id == volume_id && entry.path == path)
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn is_downloaded(&self, volume_id: u16) -> bool {
+        self.entries
+            .lock()
+            .map(|entries| entries.iter().any(|entry| entry.volume_id == volume_id))
+            .unwrap_or_default()
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<ManifestEntry>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let file: ManifestFile = serde_json::from_str(&fs::read_to_string(path)?)
+        .map_err(|err| KodanshaError::Config(err.to_string()))?;
+
+    Ok(file.entries)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// The size and hash of a file, or of the files in a folder
+fn measure(path: &Path) -> io::Result<(u64, String)> {
+    let mut files = match path.is_dir() {
+        true => fs::read_dir(path)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<io::Result<Vec<_>>>()?,
+        false => vec![path.to_path_buf()],
+    };
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    let mut bytes = 0;
+    let mut buffer = vec![0; 64 * 1024];
+
+    for file in files {
+        let mut file = File::open(file)?;
+
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..read]);
+            bytes += read as u64;
+        }
+    }
+
+    let hash = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    Ok((bytes, hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(volume_id: u16, path: &str) -> ManifestEntry {
+        ManifestEntry {
+            volume_id,
+            series_id: 1,
+            path: PathBuf::from(path),
+            format: OutputFormat::Epub,
+            bytes: 0,
+            page_count: 0,
+            hash: String::new(),
+            downloaded_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn processes_dont_overwrite_each_others_entries() {
+        let dir = std::env::temp_dir().join(format!("k-download-manifest-{}", std::process::id()));
+        let path = dir.join(MANIFEST_FILE);
+        let _ = fs::remove_dir_all(&dir);
+
+        // Loaded before either records anything, like the TUI and CLI side by side
+        let tui = Manifest::at(path.clone()).unwrap();
+        let cli = Manifest::at(path.clone()).unwrap();
+
+        tui.record(entry(1, "One.epub")).unwrap();
+        cli.record(entry(2, "Two.epub")).unwrap();
+        tui.record(entry(1, "One.epub")).unwrap();
+
+        let ids = |manifest: &Manifest| {
+            let mut ids: Vec<u16> = manifest
+                .entries()
+                .iter()
+                .map(|entry| entry.volume_id)
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(&tui), vec![1, 2]);
+        assert_eq!(ids(&Manifest::at(path).unwrap()), vec![1, 2]);
+
+        cli.refresh().unwrap();
+        assert!(cli.is_downloaded(1));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
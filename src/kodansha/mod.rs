@@ -1,11 +1,49 @@
+pub mod cache;
+pub mod chapter;
+pub mod choice;
+pub mod client;
+pub mod control;
+pub mod endpoint;
+pub mod error;
+pub mod format;
+pub mod image;
 pub mod library;
+pub mod manifest;
+pub mod output;
 pub mod page;
+pub mod progress;
+pub mod queue;
+pub mod rate_limit;
+pub mod retry;
 pub mod series;
+pub mod settings;
+pub mod sink;
+pub mod sync;
+pub mod template;
 pub mod user;
 pub mod volume;
 
-pub use library::Library;
+pub use cache::{CachedVolume, PageCache};
+pub use chapter::{Chapter, Comic};
+pub use choice::Choice;
+pub use client::KodanshaClient;
+pub use control::DownloadControl;
+pub use endpoint::Endpoint;
+pub use error::KodanshaError;
+pub use format::OutputFormat;
+pub use image::ImageType;
+pub use library::{Library, SortOrder};
+pub use manifest::{Manifest, ManifestEntry};
+pub use output::{ExistingFiles, SaveOptions, Saved};
 pub use page::Page;
-pub use series::Series;
+pub use progress::Progress;
+pub use queue::{Queue, QueueItem, QueueState, Ticket};
+pub use rate_limit::{RateLimit, RateLimiter};
+pub use retry::RetryPolicy;
+pub use series::{Creator, Series};
+pub use settings::Settings;
+pub use sink::{EpubLayout, EpubOptions, VolumeInfo, VolumeSink};
+pub use sync::{SyncAction, SyncReason};
+pub use template::PathTemplate;
 pub use user::{Credentials, User};
 pub use volume::Volume;
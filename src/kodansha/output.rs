@@ -0,0 +1,193 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use super::error::Result;
+use super::{Choice, EpubOptions, Manifest, OutputFormat, PageCache, PathTemplate, Series, Volume};
+
+/// Where and how a download is saved
+#[derive(Debug, Clone)]
+pub struct SaveOptions {
+    pub destination: PathBuf,
+    pub formats: Vec<OutputFormat>,
+    pub existing: ExistingFiles,
+    pub epub: EpubOptions,
+    /// None keeps the layout from before templates, see [`PathTemplate::default_for`]
+    pub template: Option<PathTemplate>,
+    /// Keeps fetched pages until the volume is saved, None fetches every page every time
+    pub cache: Option<PageCache>,
+    /// Where every saved volume is recorded, None leaves no record
+    pub manifest: Option<Manifest>,
+}
+
+impl SaveOptions {
+    /// Where the volume belongs in `format`, before the [`ExistingFiles`] policy has its say
+    pub fn output_path(
+        &self,
+        volume: &Volume,
+        series: Option<&Series>,
+        format: OutputFormat,
+    ) -> PathBuf {
+        let relative = match &self.template {
+            Some(template) => template.render(volume, series, format),
+            None => PathTemplate::default_for(format).render(volume, series, format),
+        };
+
+        self.destination.join(relative)
+    }
+}
+
+/// What happened to one format of a volume
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Saved {
+    Written(PathBuf),
+    /// Already there, and the [`ExistingFiles`] policy said to leave it be
+    Skipped(PathBuf),
+}
+
+impl Saved {
+    /// Where the volume already was, when every format of it was skipped
+    pub fn all_skipped(saved: &[Saved]) -> Option<&Path> {
+        let mut paths = saved.iter().map(|saved| match saved {
+            Saved::Skipped(path) => Some(path.as_path()),
+            Saved::Written(_) => None,
+        });
+
+        let first = paths.next()??;
+        paths.all(|path| path.is_some()).then_some(first)
+    }
+}
+
+/// What to do when a volume is already at its destination, set with `existing` in the settings
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExistingFiles {
+    #[default]
+    Skip,
+    Overwrite,
+    /// Saves next to it with a number added, like `Volume 1 (1).epub`
+    KeepBoth,
+}
+
+impl Choice for ExistingFiles {
+    const ALL: &'static [ExistingFiles] = &[
+        ExistingFiles::Skip,
+        ExistingFiles::Overwrite,
+        ExistingFiles::KeepBoth,
+    ];
+    const KIND: &'static str = "policy";
+
+    fn name(&self) -> &'static str {
+        match self {
+            ExistingFiles::Skip => "skip",
+            ExistingFiles::Overwrite => "overwrite",
+            ExistingFiles::KeepBoth => "keep-both",
+        }
+    }
+}
+
+impl ExistingFiles {
+    /// Where a volume that belongs at `path` should be saved, or None to skip it
+    pub fn target(&self, path: PathBuf, format: OutputFormat) -> Option<PathBuf> {
+        if !path.exists() {
+            return Some(path);
+        }
+
+        match self {
+            ExistingFiles::Skip => None,
+            ExistingFiles::Overwrite => Some(path),
+            ExistingFiles::KeepBoth => (1..)
+                .map(|number| numbered(&path, format, number))
+                .find(|path| !path.exists()),
+        }
+    }
+}
+
+fn numbered(path: &Path, format: OutputFormat, number: u32) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+    let name = match format.extension() {
+        Some(extension) => {
+            let suffix = format!(".{}", extension);
+            let stem = name.strip_suffix(&suffix).unwrap_or(&name);
+
+            format!("{} ({}){}", stem, number, suffix)
+        }
+        None => format!("{} ({})", name, number),
+    };
+
+    path.with_file_name(name)
+}
+
+impl fmt::Display for ExistingFiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name().replace('-', " "))
+    }
+}
+
+impl FromStr for ExistingFiles {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        ExistingFiles::parse(s)
+    }
+}
+
+/// Where a volume is written while it's downloading, next to where it ends up
+/// so moving it into place is a rename
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+
+    PathBuf::from(partial)
+}
+
+/// The partial files of a download, which are removed unless they're persisted,
+/// so a failed or dropped download never leaves a truncated volume behind
+#[derive(Default)]
+pub(crate) struct Partials {
+    paths: Vec<PathBuf>,
+}
+
+impl Partials {
+    /// Starts tracking the partial for `path`, clearing out anything an earlier run left there
+    pub fn add(&mut self, path: &Path) {
+        let partial = partial_path(path);
+        remove(&partial);
+
+        self.paths.push(partial);
+    }
+
+    /// Moves the finished partial for `path` into place
+    pub async fn persist(&mut self, path: &Path) -> Result<()> {
+        let partial = partial_path(path);
+
+        // Files were synced by their sink when it finished, through the handle that wrote them.
+        // Folders can't be renamed over each other, so there's a moment without either
+        if partial.is_dir() && path.exists() {
+            tokio::fs::remove_dir_all(path).await?;
+        }
+
+        tokio::fs::rename(&partial, path).await?;
+        self.paths.retain(|tracked| *tracked != partial);
+
+        Ok(())
+    }
+}
+
+impl Drop for Partials {
+    fn drop(&mut self) {
+        for partial in &self.paths {
+            remove(partial);
+        }
+    }
+}
+
+fn remove(path: &Path) {
+    let _ = match path.is_dir() {
+        true => fs::remove_dir_all(path),
+        false => fs::remove_file(path),
+    };
+}
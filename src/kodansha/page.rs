@@ -1,8 +1,8 @@
-use std::sync::{Arc, Mutex};
-
-use epub_builder::{EpubBuilder, EpubContent, ReferenceType, ZipLibrary};
 use serde::Deserialize;
 
+use super::error::Result;
+use super::{retry::OnRetry, ImageType, KodanshaClient, KodanshaError};
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct Page {
     pub url: String,
@@ -17,105 +17,49 @@ pub struct RemotePage {
 }
 
 impl Page {
-    fn image_template(page: usize, path: String) -> String {
-        format!(
-            "\
-<?xml version='1.0' encoding='utf-8'?>\n\
-<html xmlns=\"http://www.w3.org/1999/xhtml\">\n\
-  <head>\n\
-    <title>Page #{page}</title>\n\
-    <meta http-equiv=\"Content-Type\" content=\"text/html; charset=utf-8\"/>\n\
-  </head>\n\
-  <body>\n\
-    <img src=\"{path}\" alt=\"comic page #{page}\" />\n\
-  </body>\n\
-</html>\
-",
-            page = page,
-            path = path
-        )
-    }
-
-    pub async fn stream(&self, token: &String) -> anyhow::Result<Box<[u8]>> {
-        Ok(reqwest::Client::new()
-            .get(self.url.clone())
-            .header("authorization", format!("Bearer {}", token))
-            .send()
-            .await?
-            .bytes()
-            .await?
-            .as_ref()
-            .to_owned()
-            .into_boxed_slice())
-    }
-
-    pub async fn write_to_epub(
+    /// The untouched image, along with what kind of image it is
+    pub async fn stream(
         &self,
-        page_number: &usize,
-        builder: Arc<Mutex<EpubBuilder<ZipLibrary>>>,
-        token: &String,
-    ) -> anyhow::Result<Box<dyn FnOnce() -> usize + Send + 'static>> {
-        let (file_name, title, reference_type) = match page_number {
-            0 => (
-                "cover.jpeg".to_string(),
-                "Cover".to_string(),
-                ReferenceType::Cover,
-            ),
-            _ => (
-                format!("page-{}.jpeg", page_number),
-                format!("Page {}", page_number),
-                ReferenceType::Text,
-            ),
-        };
-
-        let image_path = format!("images/{}", file_name);
-        let page_number = *page_number;
-        let page_path = format!("page-{}.xhtml", page_number);
-
-        let stream = self.stream(token).await?;
-
-        {
-            let mut builder = builder.lock().unwrap();
-            match reference_type {
-                ReferenceType::Cover => {
-                    (*builder).add_cover_image(image_path.clone(), stream.as_ref(), "image/jpeg")
-                }
+        client: &KodanshaClient,
+        on_retry: &OnRetry<'_>,
+    ) -> Result<(ImageType, Box<[u8]>)> {
+        let (content_type, bytes) = client
+            .retry()
+            .run(on_retry, || client.get_bytes(&self.url))
+            .await?;
 
-                _ => (*builder).add_resource(image_path.clone(), stream.as_ref(), "image/jpeg"),
-            }
-            .unwrap();
+        match ImageType::sniff(content_type.as_deref(), &bytes) {
+            Some(image_type) => Ok((image_type, bytes)),
+            None => Err(KodanshaError::UnexpectedPayload(format!(
+                "{} isn't an image, got {}",
+                self.url,
+                content_type.as_deref().unwrap_or("no Content-Type")
+            ))),
         }
-
-        Ok(Box::new(move || {
-            let page_xml = Page::image_template(page_number, image_path);
-            let image: EpubContent<&[u8]> = EpubContent::new(page_path, page_xml.as_ref())
-                .title(title)
-                .reftype(reference_type);
-
-            let mut builder = builder.lock().unwrap();
-
-            (*builder).add_content(image).unwrap();
-
-            page_number
-        }))
     }
 }
 
 impl RemotePage {
-    pub async fn into_async(&self, token: &String) -> anyhow::Result<(usize, Page)> {
-        let page_number = self.page_number - 1;
-        let url = format!(
-            "https://api.kodansha.us/comic/{volume}/pages/{page}",
+    /// Where the page is in the volume, counting from 0 for the cover
+    pub fn index(&self) -> usize {
+        self.page_number - 1
+    }
+
+    pub async fn into_async(
+        &self,
+        client: &KodanshaClient,
+        on_retry: &OnRetry<'_>,
+    ) -> Result<(usize, Page)> {
+        let page_number = self.index();
+        let route = format!(
+            "comic/{volume}/pages/{page}",
             volume = self.comic_id,
             page = page_number
         );
 
-        let page = reqwest::Client::new()
-            .get(url)
-            .header("authorization", format!("Bearer {}", token))
-            .send()
-            .await?
-            .json::<Page>()
+        let page = client
+            .retry()
+            .run(on_retry, || client.get_json::<Page>(&route))
             .await?;
 
         Ok((page_number, page))
@@ -0,0 +1,8 @@
+/// Updates sent from a download, keyed by the volume id
+#[derive(Debug, Clone)]
+pub enum Progress {
+    /// Percentage of the pages that are done
+    Pages(u16, u8),
+    /// How many times requests for the volume's pages have been retried so far
+    Retries(u16, u32),
+}
@@ -0,0 +1,535 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::{fmt, fs, process};
+
+use serde::{Deserialize, Serialize};
+
+use super::error::Result;
+use super::user::data_dir;
+use super::KodanshaError;
+
+const QUEUE_FILE: &str = "queue.json";
+const OWNERS_DIR: &str = "owners";
+
+/// Held for as long as the process runs, so others can tell it's still downloading
+static OWNER: OnceLock<File> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueState {
+    Pending,
+    InProgress,
+    Done,
+    /// Already in the destination, at the path it was found at
+    Skipped(PathBuf),
+    /// With the reason to show the user
+    Failed(String),
+}
+
+impl QueueState {
+    /// Done, or skipped because there was nothing to do
+    pub fn is_done(&self) -> bool {
+        matches!(self, QueueState::Done | QueueState::Skipped(_))
+    }
+}
+
+impl fmt::Display for QueueState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueState::Pending => write!(f, "pending"),
+            QueueState::InProgress => write!(f, "in progress"),
+            QueueState::Done => write!(f, "done"),
+            QueueState::Skipped(path) => write!(f, "skipped, already at {}", path.display()),
+            QueueState::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QueueItem {
+    pub id: u16,
+    pub state: QueueState,
+    /// The process downloading it, while it's in progress
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<u32>,
+    /// The run downloading it, while it's in progress
+    #[serde(skip)]
+    run: Option<u64>,
+}
+
+/// Handed out when a download starts, its result is reported with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ticket {
+    pub id: u16,
+    run: u64,
+}
+
+/// The volumes waiting to be downloaded, by id, kept in the data dir so the
+/// TUI and CLI share it and it survives restarts.
+///
+/// A volume is pending until a download [starts](Queue::start) it, then done
+/// or failed once it [finishes](Queue::finish). Downloads finish in any order,
+/// so results are matched by [`Ticket`], and a result for a volume that was
+/// removed or started again since is ignored.
+///
+/// The TUI and CLI can run at the same time, so every change is made with
+/// [`Queue::update`], which applies it to what's on disk under a lock file.
+/// Volumes another running process is downloading stay in progress.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Queue {
+    items: Vec<QueueItem>,
+    #[serde(skip)]
+    runs: u64,
+}
+
+impl Queue {
+    /// Loading and updating are synchronous, so they can be done while the queue
+    /// is locked and saves from concurrent downloads land in order
+    pub fn load() -> Result<Queue> {
+        claim_owner()?;
+
+        Queue::read()
+    }
+
+    /// Applies the change to the queue on disk, with whatever other processes
+    /// changed since, and saves it. The downloads this process started keep
+    /// their tickets.
+    pub fn update<T>(&mut self, change: impl FnOnce(&mut Queue) -> T) -> Result<T> {
+        let _lock = lock(&queue_file()?)?;
+
+        let mut queue = Queue::read()?;
+        queue.adopt(self);
+
+        let changed = change(&mut queue);
+        queue.write()?;
+        *self = queue;
+
+        Ok(changed)
+    }
+
+    /// Picks up what other processes changed, without saving anything
+    pub fn refresh(&mut self) -> Result<()> {
+        let mut queue = Queue::read()?;
+        queue.adopt(self);
+        *self = queue;
+
+        Ok(())
+    }
+
+    fn read() -> Result<Queue> {
+        let path = queue_file()?;
+        if !path.exists() {
+            return Ok(Queue::default());
+        }
+
+        let mut queue: Queue = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|err| KodanshaError::Config(err.to_string()))?;
+
+        // Whatever was downloading when its process quit has to start over
+        for item in &mut queue.items {
+            if item.state == QueueState::InProgress && !item.owner.is_some_and(is_running) {
+                item.state = QueueState::Pending;
+                item.owner = None;
+            }
+        }
+
+        Ok(queue)
+    }
+
+    /// Takes over the runs of this process' downloads from the queue it was read into
+    fn adopt(&mut self, old: &Queue) {
+        self.runs = old.runs;
+
+        for item in &mut self.items {
+            if item.state != QueueState::InProgress || item.owner != Some(process::id()) {
+                continue;
+            }
+
+            item.run = old
+                .items
+                .iter()
+                .find(|old| old.id == item.id && old.state == QueueState::InProgress)
+                .and_then(|old| old.run);
+        }
+    }
+
+    fn write(&self) -> Result<()> {
+        let path = queue_file()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|err| KodanshaError::Config(err.to_string()))?;
+
+        let mut partial = path.clone();
+        partial.set_extension("json.part");
+        fs::write(&partial, json)?;
+        fs::rename(partial, path)?;
+
+        Ok(())
+    }
+
+    pub fn items(&self) -> &[QueueItem] {
+        &self.items
+    }
+
+    pub fn state(&self, id: u16) -> Option<&QueueState> {
+        self.items
+            .iter()
+            .find(|item| item.id == id)
+            .map(|item| &item.state)
+    }
+
+    /// Queues the volume, downloads that are done or failed are queued again
+    pub fn add(&mut self, id: u16) {
+        match self.items.iter_mut().find(|item| item.id == id) {
+            Some(item) => {
+                if item.state.is_done() || matches!(item.state, QueueState::Failed(_)) {
+                    item.state = QueueState::Pending;
+                }
+            }
+            None => self.items.push(QueueItem {
+                id,
+                state: QueueState::Pending,
+                owner: None,
+                run: None,
+            }),
+        }
+    }
+
+    pub fn remove(&mut self, id: u16) {
+        self.items.retain(|item| item.id != id);
+    }
+
+    /// Marks a pending or failed volume as in progress, None if it isn't waiting
+    pub fn start(&mut self, id: u16) -> Option<Ticket> {
+        let item = self.items.iter_mut().find(|item| item.id == id)?;
+        if !matches!(item.state, QueueState::Pending | QueueState::Failed(_)) {
+            return None;
+        }
+
+        self.runs += 1;
+        item.state = QueueState::InProgress;
+        item.owner = Some(process::id());
+        item.run = Some(self.runs);
+
+        Some(Ticket { id, run: self.runs })
+    }
+
+    /// Marks the volume as done or failed, unless the ticket is stale.
+    ///
+    /// Returns whether the result was recorded.
+    pub fn finish(&mut self, ticket: Ticket, result: std::result::Result<(), String>) -> bool {
+        let state = match result {
+            Ok(()) => QueueState::Done,
+            Err(reason) => QueueState::Failed(reason),
+        };
+
+        self.settle(ticket, state)
+    }
+
+    /// Marks the volume as skipped, because it was already at `path`, unless the ticket is stale
+    pub fn skip(&mut self, ticket: Ticket, path: PathBuf) -> bool {
+        self.settle(ticket, QueueState::Skipped(path))
+    }
+
+    fn settle(&mut self, ticket: Ticket, state: QueueState) -> bool {
+        let Some(item) = self.running(ticket) else {
+            return false;
+        };
+
+        item.owner = None;
+        item.run = None;
+        item.state = state;
+
+        true
+    }
+
+    /// Takes a cancelled volume off the queue, unless the ticket is stale
+    pub fn cancel(&mut self, ticket: Ticket) -> bool {
+        if self.running(ticket).is_none() {
+            return false;
+        }
+
+        self.remove(ticket.id);
+
+        true
+    }
+
+    fn running(&mut self, ticket: Ticket) -> Option<&mut QueueItem> {
+        self.items
+            .iter_mut()
+            .find(|item| item.id == ticket.id && item.run == Some(ticket.run))
+    }
+
+    /// The volumes a download should pick up, failed ones are tried again
+    pub fn waiting(&self) -> Vec<u16> {
+        self.items
+            .iter()
+            .filter(|item| matches!(item.state, QueueState::Pending | QueueState::Failed(_)))
+            .map(|item| item.id)
+            .collect()
+    }
+
+    pub fn clear_done(&mut self) {
+        self.items.retain(|item| !item.state.is_done());
+    }
+}
+
+fn queue_file() -> Result<PathBuf> {
+    let mut path = data_dir()?;
+    path.push(QUEUE_FILE);
+
+    Ok(path)
+}
+
+fn owner_file(pid: u32) -> Result<PathBuf> {
+    let mut path = data_dir()?;
+    path.push(OWNERS_DIR);
+    path.push(format!("{}.lock", pid));
+
+    Ok(path)
+}
+
+/// Only one process changes the JSON file at `path` at a time, until the
+/// returned file is dropped. The manifest is locked the same way.
+pub(super) fn lock(path: &Path) -> Result<File> {
+    let mut path = path.to_path_buf();
+    path.set_extension("json.lock");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    file.lock()?;
+
+    Ok(file)
+}
+
+/// Locks a file named after this process, the lock goes away when it exits
+fn claim_owner() -> Result<()> {
+    if OWNER.get().is_some() {
+        return Ok(());
+    }
+
+    let path = owner_file(process::id())?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    file.lock()?;
+    let _ = OWNER.set(file);
+
+    Ok(())
+}
+
+fn is_running(pid: u32) -> bool {
+    if pid == process::id() {
+        return true;
+    }
+
+    let Ok(path) = owner_file(pid) else {
+        return false;
+    };
+    let Ok(file) = File::open(&path) else {
+        return false;
+    };
+
+    match file.try_lock() {
+        Err(TryLockError::WouldBlock) => true,
+        Ok(()) => {
+            // Nobody holds it anymore, so the process is gone
+            let _ = fs::remove_file(path);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    fn queue(ids: &[u16]) -> Queue {
+        let mut queue = Queue::default();
+        for id in ids {
+            queue.add(*id);
+        }
+
+        queue
+    }
+
+    fn states(queue: &Queue) -> Vec<(u16, QueueState)> {
+        queue
+            .items()
+            .iter()
+            .map(|item| (item.id, item.state.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn only_waiting_volumes_start() {
+        let mut queue = queue(&[1, 2]);
+
+        let ticket = queue.start(1).unwrap();
+        assert_eq!(queue.start(1), None);
+        assert_eq!(queue.start(3), None);
+
+        queue.finish(ticket, Err("Server error".to_string()));
+        assert_eq!(queue.waiting(), vec![1, 2]);
+
+        let ticket = queue.start(1).unwrap();
+        queue.finish(ticket, Ok(()));
+        assert_eq!(queue.start(1), None);
+        assert_eq!(queue.waiting(), vec![2]);
+    }
+
+    #[test]
+    fn results_are_recorded_in_any_order() {
+        let mut queue = queue(&[1, 2, 3]);
+        let tickets: Vec<Ticket> = [1, 2, 3]
+            .into_iter()
+            .map(|id| queue.start(id).unwrap())
+            .collect();
+
+        assert!(queue.finish(tickets[2], Ok(())));
+        assert!(queue.finish(tickets[0], Err("Not found".to_string())));
+        assert!(queue.finish(tickets[1], Ok(())));
+
+        assert_eq!(
+            states(&queue),
+            vec![
+                (1, QueueState::Failed("Not found".to_string())),
+                (2, QueueState::Done),
+                (3, QueueState::Done),
+            ]
+        );
+    }
+
+    #[test]
+    fn stale_results_are_ignored() {
+        let mut queue = queue(&[1, 2]);
+
+        // Removed while downloading, finishing doesn't bring it back
+        let removed = queue.start(1).unwrap();
+        queue.remove(1);
+        assert!(!queue.finish(removed, Ok(())));
+        assert_eq!(queue.state(1), None);
+
+        // Cancelled, queued and started again, the old run can't touch the new one
+        let cancelled = queue.start(2).unwrap();
+        assert!(queue.cancel(cancelled));
+        queue.add(2);
+        let restarted = queue.start(2).unwrap();
+
+        assert!(!queue.finish(cancelled, Err("Cancelled".to_string())));
+        assert!(!queue.cancel(cancelled));
+        assert_eq!(queue.state(2), Some(&QueueState::InProgress));
+
+        assert!(queue.finish(restarted, Ok(())));
+        assert_eq!(queue.state(2), Some(&QueueState::Done));
+    }
+
+    #[test]
+    fn skipped_volumes_are_done() {
+        let mut queue = queue(&[1, 2]);
+        let ticket = queue.start(1).unwrap();
+
+        assert!(queue.skip(ticket, PathBuf::from("One.epub")));
+        assert!(!queue.skip(ticket, PathBuf::from("One.epub")));
+        assert_eq!(queue.waiting(), vec![2]);
+
+        queue.clear_done();
+        assert_eq!(states(&queue), vec![(2, QueueState::Pending)]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_downloads_finish_out_of_order() {
+        let ids: Vec<u16> = (1..=8).collect();
+        let queue = Arc::new(Mutex::new(queue(&ids)));
+
+        let mut tasks = Vec::new();
+        for id in ids.iter().copied() {
+            let ticket = queue.lock().await.start(id).unwrap();
+            let queue = queue.clone();
+
+            // The first volume started is the last to finish
+            tasks.push(tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(10 * (9 - id as u64))).await;
+
+                let result = match id % 3 {
+                    0 => Err(format!("Volume {} failed", id)),
+                    _ => Ok(()),
+                };
+                queue.lock().await.finish(ticket, result)
+            }));
+        }
+
+        for task in tasks {
+            assert!(task.await.unwrap());
+        }
+
+        let queue = queue.lock().await;
+        let expected: Vec<(u16, QueueState)> = ids
+            .iter()
+            .map(|id| match id % 3 {
+                0 => (*id, QueueState::Failed(format!("Volume {} failed", id))),
+                _ => (*id, QueueState::Done),
+            })
+            .collect();
+
+        assert_eq!(states(&queue), expected);
+        assert_eq!(queue.waiting(), vec![3, 6]);
+    }
+
+    #[test]
+    fn runs_are_not_saved() {
+        let mut queue = queue(&[1]);
+        queue.start(1).unwrap();
+
+        let json = serde_json::to_string(&queue).unwrap();
+        let expected = format!(
+            r#"{{"items":[{{"id":1,"state":"in-progress","owner":{}}}]}}"#,
+            process::id()
+        );
+        assert_eq!(json, expected);
+    }
+
+    #[test]
+    fn tickets_survive_rereading_the_queue() {
+        let mut queue = queue(&[1, 2]);
+        let ticket = queue.start(1).unwrap();
+        let json = serde_json::to_string(&queue).unwrap();
+
+        // Read back like another process' save, which also took 2 off the queue
+        let mut reread: Queue = serde_json::from_str(&json).unwrap();
+        reread.remove(2);
+        reread.adopt(&queue);
+
+        assert!(reread.finish(ticket, Ok(())));
+        assert_eq!(states(&reread), vec![(1, QueueState::Done)]);
+        assert_eq!(reread.start(2), None);
+    }
+
+    #[test]
+    fn dead_processes_dont_own_anything() {
+        assert!(is_running(process::id()));
+        // Nothing holds the lock of a process that was never started
+        assert!(!is_running(u32::MAX));
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+/// How many requests may be sent to Kodansha, set under `[rate_limit]` in the settings
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimit {
+    /// Sustained rate, 0 turns the limiter off
+    pub requests_per_second: f64,
+    /// How many requests may go out at once after being idle
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            requests_per_second: 10.0,
+            burst: 20,
+        }
+    }
+}
+
+/// A token bucket shared by every clone of the client, so concurrent
+/// downloads together stay below the configured rate
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Goes negative when requests are waiting for tokens that haven't been refilled yet
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        let bucket = Bucket {
+            tokens: limit.burst as f64,
+            refilled: Instant::now(),
+        };
+
+        RateLimiter {
+            limit,
+            bucket: Arc::new(Mutex::new(bucket)),
+        }
+    }
+
+    /// Takes a token, waiting until it's refilled if the bucket is empty
+    pub async fn acquire(&self) {
+        let rate = self.limit.requests_per_second;
+        if rate <= 0.0 {
+            return;
+        }
+
+        let wait = {
+            let mut bucket = match self.bucket.lock() {
+                Ok(bucket) => bucket,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(self.limit.burst.max(1) as f64);
+            bucket.refilled = now;
+
+            // Reserving the token up front keeps waiting requests in order
+            bucket.tokens -= 1.0;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(RateLimit::default())
+    }
+}
@@ -0,0 +1,80 @@
+use std::{future::Future, time::Duration};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use super::{error::Result, KodanshaError};
+
+/// Called before every retry with the attempt that failed and why
+pub type OnRetry<'a> = dyn Fn(u32, &KodanshaError) + Send + Sync + 'a;
+
+/// How often and how patiently failed requests are retried, set under `[retry]` in the settings
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts including the first one, so 1 never retries
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay_ms: 500,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub async fn run<T, F, Fut>(&self, on_retry: &OnRetry<'_>, mut request: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+
+        loop {
+            match request().await {
+                Ok(value) => return Ok(value),
+                Err(err) if attempt < self.max_attempts && is_transient(&err) => {
+                    on_retry(attempt, &err);
+                    sleep(self.delay(attempt, &err)).await;
+
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Waits as long as the server asks on a 429, up to `max_delay_ms`, otherwise
+    /// backs off exponentially with jitter so concurrent pages don't retry in lockstep
+    fn delay(&self, attempt: u32, err: &KodanshaError) -> Duration {
+        if let KodanshaError::RateLimited {
+            retry_after: Some(retry_after),
+        } = err
+        {
+            // A server asking for an hour shouldn't stall the download for an hour
+            return (*retry_after).min(Duration::from_millis(self.max_delay_ms));
+        }
+
+        let backoff = self
+            .base_delay_ms
+            .saturating_mul(2u64.saturating_pow(attempt - 1))
+            .min(self.max_delay_ms);
+        let jittered = rand::thread_rng().gen_range(backoff / 2..=backoff);
+
+        Duration::from_millis(jittered)
+    }
+}
+
+fn is_transient(err: &KodanshaError) -> bool {
+    matches!(
+        err,
+        KodanshaError::RateLimited { .. } | KodanshaError::Network(_) | KodanshaError::Server(_)
+    )
+}
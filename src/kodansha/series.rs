@@ -3,20 +3,54 @@ use std::collections::HashMap;
 use futures_util::future::join_all;
 use serde::Deserialize;
 
-use crate::{User, Volume};
+use crate::Volume;
 
-use super::Library;
+use super::error::Result;
+use super::{KodanshaClient, Library};
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Series {
     pub id: u16,
     pub title: String,
     pub genres: Vec<String>,
+    #[serde(default)]
+    pub creators: Vec<Creator>,
     pub volumes: Option<Vec<Volume>>,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct Creator {
+    pub name: String,
+    /// What they did, like "Story" or "Art"
+    #[serde(default)]
+    pub role: Option<String>,
+}
+
+impl Creator {
+    /// The MARC relator code for the role, which is what EPUB readers understand
+    pub fn relator(&self) -> Option<&'static str> {
+        let role = self.role.as_deref()?.to_ascii_lowercase();
+
+        match role.as_str() {
+            "author" | "story" | "writer" | "story and art" | "story & art" => Some("aut"),
+            "art" | "artist" | "illustrator" | "illustration" => Some("ill"),
+            "translator" | "translation" => Some("trl"),
+            "editor" | "editing" => Some("edt"),
+            _ => None,
+        }
+    }
+}
+
 impl Series {
-    pub async fn from_library(library: &Library, user: &mut User) -> Vec<Series> {
+    pub async fn get(client: &KodanshaClient, series_id: u16) -> Result<Series> {
+        let series_route = format!("series/{}/", series_id);
+
+        client.get_json::<Series>(&series_route).await
+    }
+
+    /// Every series in the library, with its volumes. Series that can't be fetched
+    /// are left out, so one missing series doesn't take the others with it.
+    pub async fn from_library(library: &Library, client: &KodanshaClient) -> Vec<Series> {
         let mut series: HashMap<u16, Vec<Volume>> = HashMap::new();
         for volume in &library.volumes {
             let mut current_series = match series.get(&volume.series_id) {
@@ -29,31 +63,20 @@ impl Series {
             series.insert(volume.series_id, current_series);
         }
 
-        let token = user.token().await.unwrap();
-        join_all(series.iter().map(move |(series_id, volumes)| {
-            let series_route = format!("https://api.kodansha.us/series/{}/", series_id);
-            let volumes = volumes.to_owned();
-            let bearer = format!("Bearer {}", token.clone());
-
-            tokio::spawn(async {
-                let mut series = reqwest::Client::new()
-                    .get(series_route)
-                    .header("authorization", bearer)
-                    .send()
-                    .await
-                    .unwrap()
-                    .json::<Series>()
-                    .await
-                    .unwrap();
-
-                series.volumes = Some(volumes);
-
-                series
-            })
+        // Series with only chapters out are looked up too, without volumes
+        for chapter in &library.chapters {
+            series.entry(chapter.series_id).or_default();
+        }
+
+        let fetched = join_all(series.into_iter().map(|(series_id, volumes)| async move {
+            let mut series = Series::get(client, series_id).await.ok()?;
+
+            series.volumes = Some(volumes);
+
+            Some(series)
         }))
-        .await
-        .into_iter()
-        .map(|series| series.unwrap())
-        .collect()
+        .await;
+
+        fetched.into_iter().flatten().collect()
     }
 }
@@ -0,0 +1,105 @@
+use std::fs;
+use std::path::PathBuf;
+
+use config::{Config, Environment, File};
+use serde::{Deserialize, Serialize};
+
+use super::endpoint::{Endpoint, DEFAULT_API_URL};
+use super::error::Result;
+use super::user::{config_dir, data_dir};
+use super::{
+    EpubOptions, ExistingFiles, KodanshaClient, OutputFormat, PathTemplate, RateLimit, RetryPolicy,
+    SortOrder,
+};
+
+const SETTINGS_FILE: &str = "settings.toml";
+/// What the TUI remembers between runs, in the data dir so `settings.toml` is left as it's written
+const STATE_FILE: &str = "state.toml";
+const ENV_PREFIX: &str = "K_DOWNLOAD";
+
+/// Settings read from `settings.toml` in the config dir, overridable with `K_DOWNLOAD_*` env vars
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Settings {
+    pub api_url: String,
+    pub connect_timeout_secs: u64,
+    pub timeout_secs: u64,
+    pub proxy: Option<String>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    #[serde(default)]
+    pub format: OutputFormat,
+    #[serde(default)]
+    pub existing: ExistingFiles,
+    #[serde(default)]
+    pub epub: EpubOptions,
+    #[serde(default)]
+    pub path_template: Option<PathTemplate>,
+    /// How the library is listed, the sort picked in the TUI is remembered over it
+    #[serde(default)]
+    pub sort: SortOrder,
+}
+
+/// The sort picked in the TUI, read back as a setting
+#[derive(Serialize)]
+struct State {
+    sort: SortOrder,
+}
+
+impl Settings {
+    pub fn load() -> Result<Settings> {
+        let settings_file = settings_file()?;
+
+        let settings = Config::builder()
+            .set_default("api_url", DEFAULT_API_URL)?
+            .set_default("connect_timeout_secs", 10)?
+            .set_default("timeout_secs", 60)?
+            .add_source(File::from(settings_file).required(false))
+            .add_source(File::from(state_file()?).required(false))
+            .add_source(
+                Environment::with_prefix(ENV_PREFIX)
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+            .build()?
+            .try_deserialize::<Settings>()?;
+
+        Ok(settings)
+    }
+
+    /// Saves the sort order to `state.toml` in the data dir, where it wins over
+    /// `settings.toml` the next time. An environment variable still wins over both.
+    pub fn remember_sort(sort: SortOrder) -> Result<()> {
+        let path = state_file()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(path, toml::to_string_pretty(&State { sort })?)?;
+
+        Ok(())
+    }
+
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint::new(self.api_url.clone())
+    }
+
+    pub fn client(&self) -> Result<KodanshaClient> {
+        KodanshaClient::new(self)
+    }
+}
+
+fn settings_file() -> Result<PathBuf> {
+    let mut settings_file = config_dir()?;
+    settings_file.push(SETTINGS_FILE);
+
+    Ok(settings_file)
+}
+
+fn state_file() -> Result<PathBuf> {
+    let mut state_file = data_dir()?;
+    state_file.push(STATE_FILE);
+
+    Ok(state_file)
+}
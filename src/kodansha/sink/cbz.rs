@@ -0,0 +1,98 @@
+use std::io::{Seek, Write};
+
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use super::{extension, page_name, SyncWriter, VolumeInfo, VolumeSink};
+use crate::kodansha::error::Result;
+
+/// Writes the pages in order with zero padded names, followed by a `ComicInfo.xml`
+pub struct CbzSink<W>
+where
+    W: Write + Seek,
+{
+    zip: ZipWriter<W>,
+    page_count: usize,
+    comic_info: String,
+}
+
+impl<W> CbzSink<W>
+where
+    W: Write + Seek,
+{
+    pub fn new(writer: W) -> CbzSink<W> {
+        CbzSink {
+            zip: ZipWriter::new(writer),
+            page_count: 0,
+            comic_info: String::new(),
+        }
+    }
+}
+
+impl<W> VolumeSink for CbzSink<W>
+where
+    W: SyncWriter + Seek + Send,
+{
+    fn begin(&mut self, info: &VolumeInfo) -> Result<()> {
+        self.page_count = info.page_count;
+        self.comic_info = comic_info(info);
+
+        Ok(())
+    }
+
+    fn add_page(&mut self, index: usize, bytes: &[u8], mime: &str) -> Result<()> {
+        // Images are compressed already, deflating them again only costs time
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+        self.zip
+            .start_file(page_name(index, self.page_count, extension(mime)), stored)?;
+        self.zip.write_all(bytes)?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        self.zip.start_file("ComicInfo.xml", deflated)?;
+        self.zip.write_all(self.comic_info.as_bytes())?;
+        self.zip.finish()?.sync()?;
+
+        Ok(())
+    }
+}
+
+/// The metadata Komga, Kavita and most comic readers look for in a CBZ
+pub fn comic_info(info: &VolumeInfo) -> String {
+    let series = match info.series {
+        Some(series) => series.title.as_str(),
+        None => info.volume.series_name.as_str(),
+    };
+    let genres = info
+        .series
+        .map(|series| series.genres.join(", "))
+        .unwrap_or_default();
+    let summary = html_escape::decode_html_entities(&info.volume.description);
+
+    format!(
+        "\
+<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+<ComicInfo xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\">\n\
+  <Title>{title}</Title>\n\
+  <Series>{series}</Series>\n\
+  <Number>{number}</Number>\n\
+  <Summary>{summary}</Summary>\n\
+  <Publisher>Kodansha</Publisher>\n\
+  <Genre>{genres}</Genre>\n\
+  <PageCount>{page_count}</PageCount>\n\
+  <LanguageISO>en</LanguageISO>\n\
+  <Manga>YesAndRightToLeft</Manga>\n\
+</ComicInfo>\n\
+",
+        title = html_escape::encode_text(&info.volume.volume_name),
+        series = html_escape::encode_text(series),
+        number = info.volume.volume_number,
+        summary = html_escape::encode_text(&summary),
+        genres = html_escape::encode_text(&genres),
+        page_count = info.page_count,
+    )
+}
@@ -0,0 +1,380 @@
+use std::io::{Seek, Write};
+
+use chrono::Utc;
+use html_escape::{encode_double_quoted_attribute as attribute, encode_text as text};
+use serde::{Deserialize, Serialize};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use super::{extension, SyncWriter, VolumeInfo, VolumeSink};
+use crate::kodansha::{error::Result, ImageType};
+
+/// Used for the viewport of pages whose size can't be read from the image
+const FALLBACK_SIZE: (u32, u32) = (1000, 1500);
+
+const CONTAINER_XML: &str = "\
+<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+<container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n\
+<rootfiles>\n\
+<rootfile full-path=\"OEBPS/content.opf\" media-type=\"application/oebps-package+xml\"/>\n\
+</rootfiles>\n\
+</container>\n\
+";
+
+const STYLESHEET: &str = "\
+html, body {\n\
+margin: 0;\n\
+padding: 0;\n\
+}\n\
+\n\
+img {\n\
+display: block;\n\
+width: 100%;\n\
+height: 100%;\n\
+object-fit: contain;\n\
+}\n\
+";
+
+/// How pages are laid out in the EPUB, set under `[epub]` in the settings
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EpubLayout {
+    /// One image per screen, turning pages right to left like the printed volume
+    #[default]
+    Fixed,
+    /// A plain book the reader lays out itself, left to right
+    Reflowable,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct EpubOptions {
+    pub layout: EpubLayout,
+}
+
+struct EpubPage {
+    index: usize,
+    image_path: String,
+    mime: String,
+}
+
+/// Writes an EPUB 3, images go into the archive as they're added and the
+/// package document listing them in reading order is written last
+pub struct EpubSink<W>
+where
+    W: Write + Seek,
+{
+    zip: ZipWriter<W>,
+    options: EpubOptions,
+    title: String,
+    /// The package metadata, minus what's only known once the book is written
+    metadata: String,
+    pages: Vec<EpubPage>,
+}
+
+impl<W> EpubSink<W>
+where
+    W: Write + Seek,
+{
+    pub fn new(writer: W, options: EpubOptions) -> Result<EpubSink<W>> {
+        let mut zip = ZipWriter::new(writer);
+
+        // Readers expect the mimetype first and uncompressed
+        zip.start_file("mimetype", stored())?;
+        zip.write_all(b"application/epub+zip")?;
+        zip.start_file("META-INF/container.xml", deflated())?;
+        zip.write_all(CONTAINER_XML.as_bytes())?;
+        zip.start_file("OEBPS/style.css", deflated())?;
+        zip.write_all(STYLESHEET.as_bytes())?;
+
+        Ok(EpubSink {
+            zip,
+            options,
+            title: String::new(),
+            metadata: String::new(),
+            pages: Vec::new(),
+        })
+    }
+
+    fn fixed(&self) -> bool {
+        self.options.layout == EpubLayout::Fixed
+    }
+
+    fn page_xhtml(&self, index: usize, image_path: &str, size: (u32, u32)) -> String {
+        let title = match index {
+            0 => "Cover".to_string(),
+            _ => format!("Page {}", index),
+        };
+        // Fixed layout pages are exactly as big as their image
+        let viewport = match self.fixed() {
+            true => format!(
+                "\n<meta name=\"viewport\" content=\"width={}, height={}\"/>",
+                size.0, size.1
+            ),
+            false => String::new(),
+        };
+
+        format!(
+            "\
+<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+<!DOCTYPE html>\n\
+<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n\
+<head>\n\
+<title>{title}</title>{viewport}\n\
+<link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\"/>\n\
+</head>\n\
+<body>\n\
+<img src=\"{path}\" alt=\"{title}\"/>\n\
+</body>\n\
+</html>\n\
+",
+            title = title,
+            viewport = viewport,
+            path = attribute(image_path),
+        )
+    }
+
+    fn package_document(&self) -> String {
+        let mut metadata = format!(
+            "{}<meta property=\"dcterms:modified\">{}</meta>\n",
+            self.metadata,
+            Utc::now().format("%Y-%m-%dT%H:%M:%SZ")
+        );
+
+        if self.fixed() {
+            metadata.push_str(
+                "\
+<meta property=\"rendition:layout\">pre-paginated</meta>\n\
+<meta property=\"rendition:orientation\">auto</meta>\n\
+<meta property=\"rendition:spread\">landscape</meta>\n\
+<meta name=\"fixed-layout\" content=\"true\"/>\n\
+<meta name=\"book-type\" content=\"comic\"/>\n\
+",
+            );
+        }
+
+        let mut manifest = String::from(
+            "\
+<item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n\
+<item id=\"style\" href=\"style.css\" media-type=\"text/css\"/>\n\
+",
+        );
+        let mut spine = String::new();
+
+        for page in &self.pages {
+            let cover = match page.index {
+                0 => " properties=\"cover-image\"",
+                _ => "",
+            };
+            let spread = match self.fixed() {
+                true => spread(page.index),
+                false => "",
+            };
+
+            manifest.push_str(&format!(
+                "<item id=\"page-{index}-image\" href=\"{image}\" media-type=\"{mime}\"{cover}/>\n\
+<item id=\"page-{index}\" href=\"page-{index}.xhtml\" media-type=\"application/xhtml+xml\"/>\n",
+                index = page.index,
+                image = attribute(&page.image_path),
+                mime = page.mime,
+                cover = cover,
+            ));
+            spine.push_str(&format!(
+                "<itemref idref=\"page-{}\"{}/>\n",
+                page.index, spread
+            ));
+        }
+
+        let direction = match self.fixed() {
+            true => " page-progression-direction=\"rtl\"",
+            false => "",
+        };
+
+        format!(
+            "\
+<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+<package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"book-id\" \
+prefix=\"rendition: http://www.idpf.org/vocab/rendition/#\">\n\
+<metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n\
+{metadata}\
+</metadata>\n\
+<manifest>\n\
+{manifest}\
+</manifest>\n\
+<spine{direction}>\n\
+{spine}\
+</spine>\n\
+</package>\n\
+",
+            metadata = metadata,
+            manifest = manifest,
+            direction = direction,
+            spine = spine,
+        )
+    }
+
+    fn navigation_document(&self) -> String {
+        let pages: String = self
+            .pages
+            .iter()
+            .map(|page| match page.index {
+                0 => "<li><a href=\"page-0.xhtml\">Cover</a></li>\n".to_string(),
+                index => format!(
+                    "<li><a href=\"page-{index}.xhtml\">Page {index}</a></li>\n",
+                    index = index
+                ),
+            })
+            .collect();
+
+        format!(
+            "\
+<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+<!DOCTYPE html>\n\
+<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n\
+<head>\n\
+<title>{title}</title>\n\
+</head>\n\
+<body>\n\
+<nav epub:type=\"toc\">\n\
+<ol>\n\
+{pages}\
+</ol>\n\
+</nav>\n\
+</body>\n\
+</html>\n\
+",
+            title = text(&self.title),
+            pages = pages,
+        )
+    }
+}
+
+impl<W> VolumeSink for EpubSink<W>
+where
+    W: SyncWriter + Seek + Send,
+{
+    fn begin(&mut self, info: &VolumeInfo) -> Result<()> {
+        self.title = info.volume.volume_name.clone();
+        self.metadata = metadata(info);
+
+        Ok(())
+    }
+
+    fn add_page(&mut self, index: usize, bytes: &[u8], mime: &str) -> Result<()> {
+        let image_path = match index {
+            0 => format!("images/cover.{}", extension(mime)),
+            _ => format!("images/page-{}.{}", index, extension(mime)),
+        };
+        let size = ImageType::from_mime(mime)
+            .unwrap_or_default()
+            .dimensions(bytes)
+            .unwrap_or(FALLBACK_SIZE);
+
+        self.zip
+            .start_file(format!("OEBPS/{}", image_path), stored())?;
+        self.zip.write_all(bytes)?;
+
+        let page = self.page_xhtml(index, &image_path, size);
+        self.zip
+            .start_file(format!("OEBPS/page-{}.xhtml", index), deflated())?;
+        self.zip.write_all(page.as_bytes())?;
+
+        self.pages.push(EpubPage {
+            index,
+            image_path,
+            mime: mime.to_string(),
+        });
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        // Added in reading order, but the spine mustn't depend on it
+        self.pages.sort_by_key(|page| page.index);
+
+        let package = self.package_document();
+        self.zip.start_file("OEBPS/content.opf", deflated())?;
+        self.zip.write_all(package.as_bytes())?;
+
+        let navigation = self.navigation_document();
+        self.zip.start_file("OEBPS/nav.xhtml", deflated())?;
+        self.zip.write_all(navigation.as_bytes())?;
+
+        self.zip.finish()?.sync()?;
+
+        Ok(())
+    }
+}
+
+/// Dublin Core plus the EPUB 3 and Calibre series metadata, so libraries sort volumes correctly
+fn metadata(info: &VolumeInfo) -> String {
+    let volume = info.volume;
+    let series = match info.series {
+        Some(series) => series.title.as_str(),
+        None => volume.series_name.as_str(),
+    };
+    let description = html_escape::decode_html_entities(&volume.description);
+
+    let mut metadata = format!(
+        "\
+<dc:identifier id=\"book-id\">{identifier}</dc:identifier>\n\
+<dc:title>{title}</dc:title>\n\
+<dc:description>{description}</dc:description>\n\
+<dc:publisher>Kodansha</dc:publisher>\n\
+<dc:language>en</dc:language>\n\
+<dc:subject>Manga</dc:subject>\n\
+<meta property=\"belongs-to-collection\" id=\"series\">{series}</meta>\n\
+<meta refines=\"#series\" property=\"collection-type\">series</meta>\n\
+<meta refines=\"#series\" property=\"group-position\">{number}</meta>\n\
+<meta name=\"calibre:series\" content=\"{series_attribute}\"/>\n\
+<meta name=\"calibre:series_index\" content=\"{number}\"/>\n\
+<meta name=\"cover\" content=\"page-0-image\"/>\n\
+",
+        identifier = info.identifier(),
+        title = text(&volume.volume_name),
+        description = text(&description),
+        series = text(series),
+        series_attribute = attribute(series),
+        number = volume.volume_number,
+    );
+
+    let genres = info.series.map(|series| series.genres.as_slice());
+    for genre in genres.unwrap_or_default() {
+        metadata.push_str(&format!("<dc:subject>{}</dc:subject>\n", text(genre)));
+    }
+
+    let creators = info.series.map(|series| series.creators.as_slice());
+    for (index, creator) in creators.unwrap_or_default().iter().enumerate() {
+        metadata.push_str(&format!(
+            "<dc:creator id=\"creator-{}\">{}</dc:creator>\n",
+            index,
+            text(&creator.name)
+        ));
+
+        if let Some(relator) = creator.relator() {
+            metadata.push_str(&format!(
+                "<meta refines=\"#creator-{}\" property=\"role\" scheme=\"marc:relators\">{}</meta>\n",
+                index, relator
+            ));
+        }
+    }
+
+    metadata
+}
+
+/// Which side of a two page spread the page goes on, the cover stands alone
+fn spread(index: usize) -> &'static str {
+    match index {
+        0 => " properties=\"rendition:page-spread-center\"",
+        index if index % 2 == 1 => " properties=\"page-spread-right\"",
+        _ => " properties=\"page-spread-left\"",
+    }
+}
+
+/// Images are compressed already, deflating them again only costs time
+fn stored() -> FileOptions {
+    FileOptions::default().compression_method(CompressionMethod::Stored)
+}
+
+fn deflated() -> FileOptions {
+    FileOptions::default().compression_method(CompressionMethod::Deflated)
+}
@@ -0,0 +1,46 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use super::{extension, page_name, VolumeInfo, VolumeSink};
+use crate::kodansha::error::Result;
+
+/// Writes the pages untouched into a folder, with the volume as `volume.json` next to them
+pub struct ImageFolderSink {
+    folder: PathBuf,
+    page_count: usize,
+}
+
+impl ImageFolderSink {
+    pub fn new(folder: PathBuf) -> ImageFolderSink {
+        ImageFolderSink {
+            folder,
+            page_count: 0,
+        }
+    }
+}
+
+impl VolumeSink for ImageFolderSink {
+    fn begin(&mut self, info: &VolumeInfo) -> Result<()> {
+        self.page_count = info.page_count;
+
+        fs::create_dir_all(&self.folder)?;
+        fs::write(
+            self.folder.join("volume.json"),
+            serde_json::to_vec_pretty(info.volume).map_err(io::Error::from)?,
+        )?;
+
+        Ok(())
+    }
+
+    fn add_page(&mut self, index: usize, bytes: &[u8], mime: &str) -> Result<()> {
+        let name = page_name(index, self.page_count, extension(mime));
+        fs::write(self.folder.join(name), bytes)?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+pub mod cbz;
+pub mod epub;
+pub mod images;
+
+pub use cbz::CbzSink;
+pub use epub::{EpubLayout, EpubOptions, EpubSink};
+pub use images::ImageFolderSink;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use super::error::Result;
+use super::{ImageType, Series, Volume};
+
+/// What's known about a volume before its pages arrive
+pub struct VolumeInfo<'a> {
+    pub volume: &'a Volume,
+    /// Only there for metadata, so it's missing if Kodansha didn't hand it out
+    pub series: Option<&'a Series>,
+    /// The comics whose pages make up the volume, usually just its own id
+    pub comics: &'a [u16],
+    pub page_count: usize,
+}
+
+impl VolumeInfo<'_> {
+    /// Tells books apart, so a run of chapters isn't mistaken for its first chapter
+    pub fn identifier(&self) -> String {
+        match self.comics {
+            [first, .., last] => format!("urn:kodansha:{}-{}", first, last),
+            [only] => format!("urn:kodansha:{}", only),
+            [] => format!("urn:kodansha:{}", self.volume.id),
+        }
+    }
+}
+
+/// An output format a volume can be written to.
+///
+/// Pages are fetched once and handed to every sink in reading order, so a CBZ
+/// lists them in order. The index says where each one goes all the same.
+pub trait VolumeSink: Send {
+    fn begin(&mut self, info: &VolumeInfo) -> Result<()>;
+
+    /// `index` starts at 0 for the cover
+    fn add_page(&mut self, index: usize, bytes: &[u8], mime: &str) -> Result<()>;
+
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// Where an archive is written, synced to disk once it's finished so a crash
+/// right after the partial is renamed can't leave an empty file behind
+pub trait SyncWriter: Write {
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl SyncWriter for BufWriter<File> {
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.get_ref().sync_all()
+    }
+}
+
+/// Zero padded so anything sorting by name gets the pages in order
+pub fn page_name(index: usize, page_count: usize, extension: &str) -> String {
+    let width = page_count.to_string().len().max(4);
+
+    format!("{:0width$}.{}", index + 1, extension, width = width)
+}
+
+/// The file extension for a page's media type, JPEG unless it says otherwise
+pub fn extension(mime: &str) -> &'static str {
+    ImageType::from_mime(mime).unwrap_or_default().extension()
+}
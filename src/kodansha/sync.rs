@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+
+use super::{Manifest, OutputFormat, SaveOptions, Volume};
+
+/// Why a sync downloads a volume
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncReason {
+    /// It was never downloaded
+    New,
+    /// It was downloaded to this path, which is gone now
+    Missing(PathBuf),
+}
+
+/// One format of a volume a sync downloads
+#[derive(Debug, Clone)]
+pub struct SyncAction {
+    pub volume: Volume,
+    pub format: OutputFormat,
+    pub reason: SyncReason,
+}
+
+/// What a sync would download: every format of a volume that's neither in the
+/// destination nor in the manifest at a path that still exists.
+///
+/// Paths are worked out without the series, templates using genres or creators
+/// only find volumes through the manifest.
+pub fn plan(volumes: &[Volume], manifest: &Manifest, options: &SaveOptions) -> Vec<SyncAction> {
+    let entries = manifest.entries();
+    let mut actions = Vec::new();
+
+    for volume in volumes {
+        for format in &options.formats {
+            if options.output_path(volume, None, *format).exists() {
+                continue;
+            }
+
+            let recorded: Vec<&PathBuf> = entries
+                .iter()
+                .filter(|entry| entry.volume_id == volume.id && entry.format == *format)
+                .map(|entry| &entry.path)
+                .collect();
+
+            // Kept elsewhere, e.g. saved next to an older copy
+            if recorded.iter().any(|path| path.exists()) {
+                continue;
+            }
+
+            let reason = match recorded.last() {
+                Some(path) => SyncReason::Missing(path.to_path_buf()),
+                None => SyncReason::New,
+            };
+
+            actions.push(SyncAction {
+                volume: volume.clone(),
+                format: *format,
+                reason,
+            });
+        }
+    }
+
+    actions
+}
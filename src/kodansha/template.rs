@@ -0,0 +1,497 @@
+use std::path::PathBuf;
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use super::{OutputFormat, Series, Volume};
+
+/// Where volumes go when no template is set, the layout from before templates existed
+const FILE_DEFAULT: &str = "{volume_name}.{ext}";
+const FOLDER_DEFAULT: &str = "{series_name}/{volume_name}";
+
+/// Characters that aren't allowed in a file name somewhere, replaced everywhere
+/// since backups tend to end up on drives shared with other systems
+const RESERVED_CHARACTERS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Most file systems allow 255 bytes per name, this leaves room for the `.part`
+/// of a download and the number `keep-both` adds
+const MAX_NAME_BYTES: usize = 200;
+
+/// Names Windows keeps for devices, whatever the extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Where a volume is saved inside the destination, like
+/// `{series}/{series} v{volume_number:02} - {volume_name}.{ext}`, set with
+/// `path_template` in the settings.
+///
+/// `/` separates folders, and placeholders are filled in with file name safe
+/// versions of the volume's and series' fields. Numbers can be zero padded
+/// with `:0` and a width. Image folders leave out `.{ext}`, and names too long for
+/// most file systems are cut short, keeping the extension.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct PathTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field, Option<usize>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// The series' title, or the volume's series name without the series
+    Series,
+    SeriesName,
+    SeriesId,
+    VolumeName,
+    VolumeNumber,
+    PageCount,
+    Description,
+    Id,
+    Genres,
+    Creators,
+    Ext,
+}
+
+impl Field {
+    const ALL: [(&'static str, Field); 11] = [
+        ("series", Field::Series),
+        ("series_name", Field::SeriesName),
+        ("series_id", Field::SeriesId),
+        ("volume_name", Field::VolumeName),
+        ("volume_number", Field::VolumeNumber),
+        ("page_count", Field::PageCount),
+        ("description", Field::Description),
+        ("id", Field::Id),
+        ("genres", Field::Genres),
+        ("creators", Field::Creators),
+        ("ext", Field::Ext),
+    ];
+
+    fn is_number(&self) -> bool {
+        matches!(
+            self,
+            Field::SeriesId | Field::VolumeNumber | Field::PageCount | Field::Id
+        )
+    }
+}
+
+impl PathTemplate {
+    /// The template used for `format` when none is set
+    pub fn default_for(format: OutputFormat) -> PathTemplate {
+        let source = match format.extension() {
+            Some(_) => FILE_DEFAULT,
+            None => FOLDER_DEFAULT,
+        };
+
+        source.parse().expect("the default templates parse")
+    }
+
+    /// The path of the volume relative to the destination.
+    ///
+    /// Without the series, the series fields fall back to what the volume knows,
+    /// and genres and creators are left empty.
+    pub fn render(
+        &self,
+        volume: &Volume,
+        series: Option<&Series>,
+        format: OutputFormat,
+    ) -> PathBuf {
+        let mut rendered = String::new();
+
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Field(Field::Ext, _) => match format.extension() {
+                    Some(extension) => rendered.push_str(extension),
+                    None => {
+                        if rendered.ends_with('.') {
+                            rendered.pop();
+                        }
+                    }
+                },
+                Part::Field(field, width) => {
+                    let value = value(*field, volume, series);
+                    let value = match width {
+                        Some(width) => format!("{:0>width$}", value, width = width),
+                        None => value,
+                    };
+
+                    rendered.push_str(&sanitize(&value));
+                }
+            }
+        }
+
+        // Empty folders and ones pointing up would put the volume outside the destination
+        let components: Vec<&str> = rendered
+            .split('/')
+            .filter(|component| !matches!(*component, "" | "." | ".."))
+            .collect();
+
+        let last = components.len().saturating_sub(1);
+        components
+            .iter()
+            .enumerate()
+            .map(|(index, component)| {
+                let extension = format
+                    .extension()
+                    .filter(|_| index == last)
+                    .map(|extension| format!(".{}", extension))
+                    .filter(|extension| component.ends_with(extension.as_str()));
+
+                match extension {
+                    Some(extension) => {
+                        let stem = &component[..component.len() - extension.len()];
+                        let stem = truncate(stem, MAX_NAME_BYTES - extension.len());
+
+                        format!("{}{}", stem, extension)
+                    }
+                    None => truncate(component, MAX_NAME_BYTES),
+                }
+            })
+            .collect()
+    }
+}
+
+fn value(field: Field, volume: &Volume, series: Option<&Series>) -> String {
+    match field {
+        Field::Series => series
+            .map(|series| series.title.clone())
+            .unwrap_or_else(|| volume.series_name.clone()),
+        Field::SeriesName => volume.series_name.clone(),
+        Field::SeriesId => volume.series_id.to_string(),
+        Field::VolumeName => volume.volume_name.clone(),
+        Field::VolumeNumber => volume.volume_number.to_string(),
+        Field::PageCount => volume.page_count.to_string(),
+        Field::Description => html_escape::decode_html_entities(&volume.description).to_string(),
+        Field::Id => volume.id.to_string(),
+        Field::Genres => series
+            .map(|series| series.genres.join(", "))
+            .unwrap_or_default(),
+        Field::Creators => series
+            .map(|series| {
+                let names: Vec<&str> = series
+                    .creators
+                    .iter()
+                    .map(|creator| creator.name.as_str())
+                    .collect();
+
+                names.join(", ")
+            })
+            .unwrap_or_default(),
+        Field::Ext => String::new(),
+    }
+}
+
+/// Makes a value safe to use as (part of) a file name on any system
+pub fn sanitize(value: &str) -> String {
+    let sanitized: String = value
+        .chars()
+        .map(|character| match character {
+            character if RESERVED_CHARACTERS.contains(&character) => '_',
+            character if character.is_control() => ' ',
+            character => character,
+        })
+        .collect();
+
+    // Windows drops trailing dots and spaces, which would make two names the same
+    let sanitized = sanitized.trim().trim_end_matches('.').to_string();
+
+    let stem = sanitized.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        return format!("_{}", sanitized);
+    }
+
+    sanitized
+}
+
+/// Cuts a name down to `max` bytes, on a character boundary, keeping it sanitized
+fn truncate(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+
+    let mut end = max;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    // The cut can leave a trailing space or dot, which Windows drops
+    name[..end].trim_end().trim_end_matches('.').to_string()
+}
+
+impl FromStr for PathTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut characters = s.chars();
+
+        while let Some(character) = characters.next() {
+            match character {
+                '{' if characters.as_str().starts_with('{') => {
+                    characters.next();
+                    literal.push('{');
+                }
+                '}' if characters.as_str().starts_with('}') => {
+                    characters.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = characters.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or(format!("Unclosed {{ in path template {}", s))?;
+                    let placeholder = &rest[..end];
+                    characters = rest[end + 1..].chars();
+
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(placeholder_part(placeholder)?);
+                }
+                '}' => return Err(format!("Unopened }} in path template {}", s)),
+                character => literal.push(character),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        if parts.is_empty() {
+            return Err("The path template is empty".to_string());
+        }
+
+        Ok(PathTemplate {
+            source: s.to_string(),
+            parts,
+        })
+    }
+}
+
+fn placeholder_part(placeholder: &str) -> std::result::Result<Part, String> {
+    let (name, width) = match placeholder.split_once(':') {
+        Some((name, width)) => (name, Some(width)),
+        None => (placeholder, None),
+    };
+
+    let field = Field::ALL
+        .iter()
+        .find(|(field_name, _)| *field_name == name)
+        .map(|(_, field)| *field)
+        .ok_or_else(|| {
+            let names: Vec<&str> = Field::ALL.iter().map(|(name, _)| *name).collect();
+            format!(
+                "Unknown placeholder {{{}}}, expected one of {}",
+                name,
+                names.join(", ")
+            )
+        })?;
+
+    let width = match width {
+        None => None,
+        Some(width) if field.is_number() => Some(
+            width
+                .strip_prefix('0')
+                .and_then(|width| width.parse().ok())
+                .ok_or(format!("Expected a width like :02 in {{{}}}", placeholder))?,
+        ),
+        Some(_) => return Err(format!("Only numbers can be padded, not {{{}}}", name)),
+    };
+
+    Ok(Part::Field(field, width))
+}
+
+impl TryFrom<String> for PathTemplate {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<PathTemplate> for String {
+    fn from(template: PathTemplate) -> Self {
+        template.source
+    }
+}
+
+impl fmt::Display for PathTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::kodansha::Choice;
+
+    fn volume(series_name: &str, volume_name: &str, volume_number: u8) -> Volume {
+        Volume {
+            series_name: series_name.to_string(),
+            volume_name: volume_name.to_string(),
+            volume_number,
+            page_count: 192,
+            description: "A &amp; B".to_string(),
+            id: 7,
+            series_id: 3,
+        }
+    }
+
+    fn series() -> Series {
+        Series {
+            id: 3,
+            title: "Titan: Before".to_string(),
+            genres: vec!["Action".to_string(), "Drama".to_string()],
+            creators: Vec::new(),
+            volumes: None,
+        }
+    }
+
+    #[test]
+    fn placeholders_are_parsed() {
+        let template: PathTemplate = "{series}/{{v{volume_number:02}}} - {volume_name}.{ext}"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            template.parts,
+            vec![
+                Part::Field(Field::Series, None),
+                Part::Literal("/{v".to_string()),
+                Part::Field(Field::VolumeNumber, Some(2)),
+                Part::Literal("} - ".to_string()),
+                Part::Field(Field::VolumeName, None),
+                Part::Literal(".".to_string()),
+                Part::Field(Field::Ext, None),
+            ]
+        );
+        assert_eq!(
+            template.to_string(),
+            "{series}/{{v{volume_number:02}}} - {volume_name}.{ext}"
+        );
+    }
+
+    #[test]
+    fn bad_templates_are_rejected() {
+        for template in [
+            "",
+            "{volume}",
+            "{volume_name",
+            "volume_name}",
+            "{volume_name:02}",
+            "{volume_number:2}",
+            "{volume_number:0x}",
+        ] {
+            assert!(
+                template.parse::<PathTemplate>().is_err(),
+                "{} should be rejected",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn volumes_are_rendered_into_folders() {
+        let template: PathTemplate = "{series}/{series} v{volume_number:02} - {volume_name}.{ext}"
+            .parse()
+            .unwrap();
+        let volume = volume("Titan", "Titan 3", 3);
+
+        assert_eq!(
+            template.render(&volume, None, OutputFormat::Cbz),
+            Path::new("Titan/Titan v03 - Titan 3.cbz")
+        );
+        assert_eq!(
+            template.render(&volume, Some(&series()), OutputFormat::Epub),
+            Path::new("Titan_ Before/Titan_ Before v03 - Titan 3.epub")
+        );
+        assert_eq!(
+            template.render(&volume, None, OutputFormat::Images),
+            Path::new("Titan/Titan v03 - Titan 3")
+        );
+    }
+
+    #[test]
+    fn values_cant_leave_the_destination() {
+        let template: PathTemplate = "{series_name}/../{volume_name}/{genres}.{ext}"
+            .parse()
+            .unwrap();
+        let volume = volume("..", "../../etc/passwd", 1);
+
+        assert_eq!(
+            template.render(&volume, None, OutputFormat::Epub),
+            Path::new(".._.._etc_passwd/.epub")
+        );
+    }
+
+    #[test]
+    fn values_are_sanitized() {
+        assert_eq!(sanitize("Who? Me: <Yes> / No"), "Who_ Me_ _Yes_ _ No");
+        assert_eq!(sanitize("Line\nbreak\t"), "Line break");
+        assert_eq!(sanitize("Trailing dots..."), "Trailing dots");
+        assert_eq!(sanitize("con"), "_con");
+        assert_eq!(sanitize("LPT1.txt"), "_LPT1.txt");
+        assert_eq!(sanitize("Console"), "Console");
+    }
+
+    #[test]
+    fn long_names_are_cut_short() {
+        let template: PathTemplate = "{description}/{volume_name} {description}.{ext}"
+            .parse()
+            .unwrap();
+        let mut volume = volume("Titan", "Titan 3", 3);
+        volume.description = "進撃の巨人 ".repeat(100);
+
+        for &format in OutputFormat::ALL {
+            let path = template.render(&volume, None, format);
+
+            for component in path.iter() {
+                let component = component.to_str().unwrap();
+                assert!(
+                    component.len() <= MAX_NAME_BYTES,
+                    "{} is too long",
+                    component
+                );
+                assert!(!component.ends_with(' '));
+            }
+
+            if let Some(extension) = format.extension() {
+                assert_eq!(path.extension().unwrap(), extension);
+            }
+        }
+    }
+
+    #[test]
+    fn the_defaults_keep_the_old_layout() {
+        let volume = volume("Titan", "Titan 3", 3);
+
+        assert_eq!(
+            PathTemplate::default_for(OutputFormat::Epub).render(&volume, None, OutputFormat::Epub),
+            Path::new("Titan 3.epub")
+        );
+        assert_eq!(
+            PathTemplate::default_for(OutputFormat::Images).render(
+                &volume,
+                None,
+                OutputFormat::Images
+            ),
+            Path::new("Titan/Titan 3")
+        );
+    }
+}
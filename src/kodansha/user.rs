@@ -1,10 +1,9 @@
+use std::cmp::Ordering;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use chrono::{DateTime, Duration, Utc};
 
-use anyhow::{anyhow, Ok};
-use reqwest;
 use serde::{Deserialize, Serialize};
 use tokio::{
     fs::{create_dir, File},
@@ -13,13 +12,30 @@ use tokio::{
 
 use crate::Volume;
 
-use super::Library;
+use super::error::Result;
+use super::{Chapter, KodanshaClient, KodanshaError, Library};
 
 const CONFIG_DIR: &str = "k-download";
 const CONFIG_FILE: &str = "config.toml";
 const TOKEN_FILE: &str = "token.toml";
 const DOWNLOAD_FILE: &str = "download.toml";
 
+/// Where the token, queue, manifest and cache are kept
+pub(crate) fn data_dir() -> Result<PathBuf> {
+    let mut path = dirs::data_dir().ok_or(KodanshaError::Config("No data dir".to_string()))?;
+    path.push(CONFIG_DIR);
+
+    Ok(path)
+}
+
+/// Where the credentials, settings and download folder are kept
+pub(crate) fn config_dir() -> Result<PathBuf> {
+    let mut path = dirs::config_dir().ok_or(KodanshaError::Config("No config dir".to_string()))?;
+    path.push(CONFIG_DIR);
+
+    Ok(path)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Credentials {
     #[serde(alias = "UserName")]
@@ -63,6 +79,7 @@ pub struct User {
     refresh: String,
     expirery: DateTime<Utc>,
     library: Arc<Mutex<Option<Library>>>,
+    client: KodanshaClient,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -71,22 +88,20 @@ struct DownloadFoler {
 }
 
 impl User {
-    pub async fn new(username: String, password: String) -> User {
+    pub async fn new(client: KodanshaClient, username: String, password: String) -> Result<User> {
         let creds = Credentials { username, password };
 
-        reqwest::Client::new()
-            .post("https://api.kodansha.us/account/token")
-            .json(&creds)
-            .send()
-            .await
-            .unwrap()
-            .json::<KodanshaUser>()
-            .await
-            .unwrap()
-            .into()
+        let mut user: User = client
+            .post_json::<_, KodanshaUser>("account/token", &creds)
+            .await?
+            .into();
+
+        user.client = client;
+
+        Ok(user)
     }
 
-    async fn persist(&self, path: &str) -> anyhow::Result<()> {
+    async fn persist(&self, path: &str) -> Result<()> {
         let mut file = File::create(path).await?;
         let stored: StoredUser = self.into();
 
@@ -101,15 +116,21 @@ impl User {
         self.library.clone()
     }
 
-    pub async fn token(&mut self) -> anyhow::Result<String> {
-        let mut data_dir = dirs::data_dir().ok_or(anyhow!("No data dir"))?;
-        data_dir.push(CONFIG_DIR);
+    /// The shared client, authorized with a token that's refreshed if needed
+    pub async fn client(&mut self) -> Result<KodanshaClient> {
+        let token = self.token().await?;
+
+        Ok(self.client.authorized(token))
+    }
+
+    pub async fn token(&mut self) -> Result<String> {
+        let mut data_dir = data_dir()?;
         data_dir.push(TOKEN_FILE);
 
         let token_file = data_dir.into_os_string();
-        let token_path = token_file
-            .to_str()
-            .ok_or(anyhow!("Couldn't convert options path to path"))?;
+        let token_path = token_file.to_str().ok_or(KodanshaError::Config(
+            "Couldn't convert options path to path".to_string(),
+        ))?;
 
         let now = Utc::now();
         if now.timestamp() < self.expirery.timestamp() {
@@ -117,13 +138,15 @@ impl User {
         }
 
         let refresh: KodanshaRefreshRequest = self.into();
-        let request = reqwest::Client::new()
-            .post("https://api.kodansha.us/account/token")
-            .json(&refresh)
-            .send()
-            .await?;
-
-        let refresh = request.json::<KodanshaRefresh>().await?;
+        let refresh = self
+            .client
+            .post_json::<_, KodanshaRefresh>("account/token", &refresh)
+            .await
+            .map_err(|err| match err {
+                // A rejected refresh token means the session is over, not that the password is wrong
+                KodanshaError::AuthFailed => KodanshaError::TokenExpired,
+                err => err,
+            })?;
 
         self.token = refresh.access_token;
         self.expirery = expirery(refresh.expires_in);
@@ -132,43 +155,50 @@ impl User {
         Ok(self.token.clone())
     }
 
-    pub async fn load_library(&mut self) -> anyhow::Result<()> {
+    pub async fn load_library(&mut self) -> Result<()> {
         // Prefetch so we don't indefinetly hold the mutex in an async context
-        let token = self.token().await?;
-        let fetched_library = Library {
-            volumes: reqwest::Client::new()
-                .get("https://api.kodansha.us/mycomics/")
-                .header("authorization", format!("Bearer {}", token))
-                .send()
-                .await?
-                .json::<Vec<Volume<Option<String>>>>()
-                .await?
-                .into_iter()
-                // Filters away chapters
-                .filter_map(|volume| match volume.volume_name {
-                    Some(volume_name) => Some(Volume {
-                        series_name: volume.series_name,
-                        volume_name,
-                        volume_number: volume.volume_number,
-                        page_count: volume.page_count,
-                        description: volume.description,
-                        id: volume.id,
-                        series_id: volume.series_id,
-                    }),
-
-                    None => None,
-                })
-                .collect(),
-        };
+        let client = self.client().await?;
+        let mut fetched_library = Library::default();
+
+        // Volumes and chapters come in one list, chapters are the ones without a volume name
+        for comic in client
+            .get_json::<Vec<serde_json::Value>>("mycomics/")
+            .await?
+        {
+            let unexpected =
+                |err: serde_json::Error| KodanshaError::UnexpectedPayload(err.to_string());
+
+            let id = match comic.get("volumeName").is_some_and(|name| !name.is_null()) {
+                true => {
+                    let volume = serde_json::from_value::<Volume>(comic).map_err(unexpected)?;
+                    let id = volume.id;
+                    fetched_library.volumes.push(volume);
+                    id
+                }
+                false => {
+                    let chapter = serde_json::from_value::<Chapter>(comic).map_err(unexpected)?;
+                    let id = chapter.id;
+                    fetched_library.chapters.push(chapter);
+                    id
+                }
+            };
+            fetched_library.added.push(id);
+        }
+
+        fetched_library.chapters.sort_by(|a, b| {
+            (&a.series_name, a.series_id)
+                .cmp(&(&b.series_name, b.series_id))
+                .then(a.order().partial_cmp(&b.order()).unwrap_or(Ordering::Equal))
+        });
 
         let library = { self.library.lock() };
         match library {
-            Result::Ok(mut library) => {
+            Ok(mut library) => {
                 *library = Some(fetched_library);
 
                 Ok(())
             }
-            Err(_) => Result::Err(anyhow!("Couldn't read the mutex")),
+            Err(_) => Err(KodanshaError::Config("Couldn't read the mutex".to_string())),
         }
     }
 }
@@ -177,25 +207,24 @@ impl Credentials {
     pub fn new(username: String, password: String) -> Credentials {
         Credentials { username, password }
     }
-    pub async fn from_config() -> anyhow::Result<Credentials> {
-        let mut data_dir = dirs::config_dir().ok_or(anyhow!("No data dir"))?;
-        data_dir.push(CONFIG_DIR);
+    pub async fn from_config() -> Result<Credentials> {
+        let mut data_dir = config_dir()?;
 
         let option_dir = data_dir.clone().into_os_string();
-        let option_str = option_dir
-            .to_str()
-            .ok_or(anyhow!("Couldn't convert options path to path"))?;
+        let option_str = option_dir.to_str().ok_or(KodanshaError::Config(
+            "Couldn't convert options path to path".to_string(),
+        ))?;
         if !Path::new(option_str).exists() {
-            return Err(anyhow!("File doesn't exist"));
+            return Err(KodanshaError::Config("File doesn't exist".to_string()));
         }
 
         data_dir.push(CONFIG_FILE);
 
         let config_file = data_dir.into_os_string();
 
-        let config_dir = config_file
-            .to_str()
-            .ok_or(anyhow!("Error converting dir to str"))?;
+        let config_dir = config_file.to_str().ok_or(KodanshaError::Config(
+            "Error converting dir to str".to_string(),
+        ))?;
 
         let creds = match Path::new(config_dir).exists() {
             true => {
@@ -204,24 +233,23 @@ impl Credentials {
                 toml::from_str::<Credentials>(&contest)?
             }
             false => {
-                return Err(anyhow!(
+                return Err(KodanshaError::Config(format!(
                     "Couldn't pass the file at {}, pleaser consider deleting it or edit it.",
                     config_dir
-                ))
+                )))
             }
         };
 
         Ok(creds)
     }
 
-    pub async fn write_user(username: String, password: String) -> anyhow::Result<Credentials> {
-        let mut data_dir = dirs::config_dir().ok_or(anyhow!("No data dir"))?;
-        data_dir.push(CONFIG_DIR);
+    pub async fn write_user(username: String, password: String) -> Result<Credentials> {
+        let mut data_dir = config_dir()?;
 
         let option_dir = data_dir.clone().into_os_string();
-        let option_str = option_dir
-            .to_str()
-            .ok_or(anyhow!("Couldn't convert options path to path"))?;
+        let option_str = option_dir.to_str().ok_or(KodanshaError::Config(
+            "Couldn't convert options path to path".to_string(),
+        ))?;
         if !Path::new(option_str).exists() {
             create_dir(option_str).await?;
         }
@@ -230,9 +258,9 @@ impl Credentials {
 
         let config_file = data_dir.into_os_string();
 
-        let config_str = config_file
-            .to_str()
-            .ok_or(anyhow!("Error converting dir to str"))?;
+        let config_str = config_file.to_str().ok_or(KodanshaError::Config(
+            "Error converting dir to str".to_string(),
+        ))?;
         let creds = Credentials { username, password };
         let mut file = File::create(config_str).await?;
 
@@ -242,31 +270,49 @@ impl Credentials {
         Ok(creds)
     }
 
-    pub async fn login(self) -> anyhow::Result<User> {
-        let mut data_dir = dirs::data_dir().ok_or(anyhow!("No data dir"))?;
-        data_dir.push(CONFIG_DIR);
+    pub async fn login(self, client: KodanshaClient) -> Result<User> {
+        let mut data_dir = data_dir()?;
         data_dir.push(TOKEN_FILE);
 
         let token_file = data_dir.into_os_string();
-        let token_path = token_file
-            .to_str()
-            .ok_or(anyhow!("Couldn't convert options path to path"))?;
+        let token_path = token_file.to_str().ok_or(KodanshaError::Config(
+            "Couldn't convert options path to path".to_string(),
+        ))?;
 
         let user = if Path::new(token_path).exists() {
             let token = tokio::fs::read_to_string(token_path).await?;
             let stored = toml::from_str::<StoredUser>(&token)?;
 
-            stored.into()
-        } else {
-            let user = User::new(self.username, self.password).await;
-
-            user.persist(token_path).await?;
+            let mut user: User = stored.into();
+            user.client = client;
 
             user
+        } else {
+            self.fresh_login(client).await?
         };
 
         Ok(user)
     }
+
+    /// Logs in without looking at the stored token, and stores the new one
+    pub async fn fresh_login(self, client: KodanshaClient) -> Result<User> {
+        let mut data_dir = data_dir()?;
+        if !data_dir.exists() {
+            create_dir(&data_dir).await?;
+        }
+        data_dir.push(TOKEN_FILE);
+
+        let token_file = data_dir.into_os_string();
+        let token_path = token_file.to_str().ok_or(KodanshaError::Config(
+            "Couldn't convert options path to path".to_string(),
+        ))?;
+
+        let user = User::new(client, self.username, self.password).await?;
+
+        user.persist(token_path).await?;
+
+        Ok(user)
+    }
 }
 
 impl From<KodanshaUser> for User {
@@ -277,6 +323,7 @@ impl From<KodanshaUser> for User {
             library: Arc::default(),
             expirery,
             refresh: value.refresh,
+            client: KodanshaClient::default(),
         }
     }
 }
@@ -288,6 +335,7 @@ impl From<&StoredUser> for User {
             refresh: value.refresh.clone(),
             expirery: value.expirery,
             library: Arc::default(),
+            client: KodanshaClient::default(),
         }
     }
 }
@@ -299,6 +347,7 @@ impl From<StoredUser> for User {
             refresh: value.refresh,
             expirery: value.expirery,
             library: Arc::default(),
+            client: KodanshaClient::default(),
         }
     }
 }
@@ -330,15 +379,14 @@ fn expirery(expires_in: i64) -> DateTime<Utc> {
     now + offset - slack
 }
 
-pub async fn download_dir() -> anyhow::Result<Option<PathBuf>> {
-    let mut data_dir = dirs::config_dir().ok_or(anyhow!("No data dir"))?;
-    data_dir.push(CONFIG_DIR);
+pub async fn download_dir() -> Result<Option<PathBuf>> {
+    let mut data_dir = config_dir()?;
     data_dir.push(DOWNLOAD_FILE);
 
     let download_file = data_dir.into_os_string();
-    let download_path = download_file
-        .to_str()
-        .ok_or(anyhow!("Couldn't convert options path to path"))?;
+    let download_path = download_file.to_str().ok_or(KodanshaError::Config(
+        "Couldn't convert options path to path".to_string(),
+    ))?;
 
     let downlaod = if Path::new(download_path).exists() {
         let download = tokio::fs::read_to_string(download_path).await?;
@@ -355,21 +403,20 @@ pub async fn download_dir() -> anyhow::Result<Option<PathBuf>> {
     Ok(downlaod)
 }
 
-pub async fn set_download_dir(path: &Path) -> anyhow::Result<()> {
-    let mut data_dir = dirs::config_dir().ok_or(anyhow!("No data dir"))?;
-    data_dir.push(CONFIG_DIR);
+pub async fn set_download_dir(path: &Path) -> Result<()> {
+    let mut data_dir = config_dir()?;
     data_dir.push(DOWNLOAD_FILE);
 
     let download_file = data_dir.into_os_string();
-    let download_path = download_file
-        .to_str()
-        .ok_or(anyhow!("Couldn't convert options path to path"))?;
+    let download_path = download_file.to_str().ok_or(KodanshaError::Config(
+        "Couldn't convert options path to path".to_string(),
+    ))?;
 
     let downlad = DownloadFoler {
         path: path
             .to_owned()
             .to_str()
-            .ok_or(anyhow!("Couldn't convert"))?
+            .ok_or(KodanshaError::Config("Couldn't convert".to_string()))?
             .to_string(),
     };
 
@@ -1,16 +1,22 @@
-use std::sync::Arc;
-use std::sync::Mutex;
-use std::time::Duration;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
 
-use anyhow::Result;
-use epub_builder::{EpubBuilder, EpubVersion, ZipLibrary};
-use futures::future::join_all;
-use serde::Deserialize;
-use tokio::{sync::mpsc::Sender, time::sleep};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
 
-use super::page::RemotePage;
+use super::cache::PageCache;
+use super::error::Result;
+use super::output::{Partials, SaveOptions, Saved};
+use super::sink::{VolumeInfo, VolumeSink};
+use super::{
+    page::RemotePage, KodanshaClient, KodanshaError, ManifestEntry, OutputFormat, Progress, Series,
+};
 
-#[derive(Deserialize, Debug, Clone)]
+/// How many pages of a volume are downloaded at the same time
+const PAGES_IN_FLIGHT: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Volume<VolumeName = String> {
     pub series_name: String,
@@ -23,84 +29,229 @@ pub struct Volume<VolumeName = String> {
 }
 
 impl Volume {
-    pub async fn get(url_id: u16) -> Result<Volume> {
-        let volume_route = format!("https://api.kodansha.us/comic/{}/", url_id);
-        let volume = reqwest::get(volume_route)
-            .await
-            .unwrap()
-            .json::<Volume>()
-            .await
-            .unwrap();
+    pub async fn get(client: &KodanshaClient, url_id: u16) -> Result<Volume> {
+        let volume_route = format!("comic/{}/", url_id);
+        client.get_json::<Volume>(&volume_route).await
+    }
 
-        Ok(volume)
+    /// Downloads the volume once and saves it in every format that isn't skipped
+    pub async fn save(
+        &self,
+        options: &SaveOptions,
+        client: &KodanshaClient,
+        progress: Sender<Progress>,
+    ) -> Result<Vec<Saved>> {
+        self.save_comics(&[self.id], options, client, progress)
+            .await
     }
 
-    pub async fn write_epub_to<W>(
+    /// Saves the pages of `comics`, one after the other, as this volume.
+    ///
+    /// This is how a run of chapters ends up in one book, see [`Chapter::merge`](super::Chapter::merge).
+    pub async fn save_comics(
         &self,
-        token: &String,
-        writer: &mut W,
-        progress: Sender<(u16, u8)>,
-    ) -> anyhow::Result<()>
-    where
-        W: std::io::Write,
-    {
-        let zip = ZipLibrary::new().unwrap();
-        let mut builder = EpubBuilder::new(zip).unwrap();
-
-        builder
-            .metadata("title", self.volume_name.clone())
-            .unwrap()
-            .metadata(
-                "description",
-                self.description.clone().replace("rsquo", "apos"),
-            )
-            //.unwrap()
-            //.metadata("series", volume.series_name.clone())
-            .unwrap()
-            .metadata("subject", "Manga")
-            .unwrap()
-            .epub_version(EpubVersion::V30);
-
-        let builder = Arc::new(Mutex::new(builder));
-
-        let page_requests = self.page_links(token).await?;
-        let page_count = self.page_count as usize;
-
-        for chunks in page_requests.chunks(10) {
-            let chunks = chunks.iter().map(|page| async {
-                let (page_number, page) = page.into_async(token).await?;
-
-                page.write_to_epub(&page_number, Arc::clone(&builder), token)
-                    .await
-            });
-
-            let pages = join_all(chunks).await;
-            sleep(Duration::from_millis(10)).await;
-
-            for fun in pages.into_iter().map(|fun| fun.unwrap()) {
-                let page = fun() + 1;
-                let decimal: f32 = page as f32 / page_count as f32;
-                let percent = decimal * 100.0f32;
-
-                progress.send((self.id, percent as u8)).await?;
+        comics: &[u16],
+        options: &SaveOptions,
+        client: &KodanshaClient,
+        progress: Sender<Progress>,
+    ) -> Result<Vec<Saved>> {
+        let mut saved = Vec::new();
+        let mut targets = Vec::new();
+
+        // The series is only there for metadata and paths, so the volume isn't lost over it
+        let series = Series::get(client, self.series_id).await.ok();
+
+        for format in &options.formats {
+            let path = options.output_path(self, series.as_ref(), *format);
+
+            match options.existing.target(path.clone(), *format) {
+                Some(target) => targets.push((*format, target)),
+                None => {
+                    self.record_existing(comics, options, *format, &path)
+                        .await?;
+                    saved.push(Saved::Skipped(path));
+                }
             }
         }
 
-        let mut builder = builder.lock().unwrap();
-        let _ = builder.generate(writer);
+        if targets.is_empty() {
+            return Ok(saved);
+        }
+
+        let mut partials = Partials::default();
+
+        let page_count = {
+            let mut sinks = Vec::with_capacity(targets.len());
+            for (format, path) in &targets {
+                partials.add(path);
+                sinks.push(format.sink(path, &options.epub)?);
+            }
+
+            let cache = options.cache.as_ref();
+            self.write_to_sinks(client, comics, &mut sinks, series.as_ref(), cache, progress)
+                .await?
+        };
+
+        for (format, path) in targets {
+            partials.persist(&path).await?;
+
+            // Every chapter of a merged run is in the book, so each is recorded as downloaded
+            if let Some(manifest) = &options.manifest {
+                let entry = ManifestEntry::new(self, format, &path, page_count).await?;
+
+                for comic in comics {
+                    manifest.record(ManifestEntry {
+                        volume_id: *comic,
+                        ..entry.clone()
+                    })?;
+                }
+            }
+
+            saved.push(Saved::Written(path));
+        }
+
+        if let Some(cache) = &options.cache {
+            for comic in comics {
+                cache.remove(*comic).await?;
+            }
+        }
+
+        Ok(saved)
+    }
+
+    /// Records a skipped volume that isn't in the manifest yet, it's downloaded all the same
+    async fn record_existing(
+        &self,
+        comics: &[u16],
+        options: &SaveOptions,
+        format: OutputFormat,
+        path: &Path,
+    ) -> Result<()> {
+        let Some(manifest) = &options.manifest else {
+            return Ok(());
+        };
+
+        let missing: Vec<u16> = comics
+            .iter()
+            .copied()
+            .filter(|comic| !manifest.contains(*comic, path))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let entry = ManifestEntry::new(self, format, path, self.page_count as usize).await?;
+        for comic in missing {
+            manifest.record(ManifestEntry {
+                volume_id: comic,
+                ..entry.clone()
+            })?;
+        }
+
         Ok(())
     }
 
-    pub async fn page_links(&self, token: &String) -> reqwest::Result<Vec<RemotePage>> {
-        let volume_route = format!("https://api.kodansha.us/comic/{}/pages", self.id);
-        let bearer = format!("Bearer {}", &token);
+    /// Fetches every page once and hands it to each sink, so several formats cost one download.
+    ///
+    /// The pages of `comics` follow each other, usually that's just the volume's id.
+    /// Pages in the cache aren't fetched again, and fetched pages are added to it.
+    /// Returns how many pages were written.
+    pub async fn write_to_sinks(
+        &self,
+        client: &KodanshaClient,
+        comics: &[u16],
+        sinks: &mut [Box<dyn VolumeSink + '_>],
+        series: Option<&Series>,
+        cache: Option<&PageCache>,
+        progress: Sender<Progress>,
+    ) -> Result<usize> {
+        // Each comic numbers its pages from the start, so later ones are moved after the earlier
+        let mut page_requests = Vec::new();
+        for comic in comics {
+            let offset = page_requests.len();
+            let links = page_links(client, *comic).await?;
 
-        reqwest::Client::new()
-            .get(volume_route)
-            .header("authorization", bearer.clone())
-            .send()
-            .await?
-            .json::<Vec<RemotePage>>()
-            .await
+            page_requests.extend(links.into_iter().map(|page| (*comic, offset, page)));
+        }
+        page_requests.sort_by_key(|(_, offset, page)| offset + page.index());
+        let page_count = page_requests.len();
+
+        let info = VolumeInfo {
+            volume: self,
+            series,
+            comics,
+            page_count,
+        };
+
+        for sink in sinks.iter_mut() {
+            sink.begin(&info)?;
+        }
+
+        let retries = AtomicU32::new(0);
+        let on_retry = |_attempt: u32, _err: &KodanshaError| {
+            let retries = retries.fetch_add(1, Ordering::Relaxed) + 1;
+            // Dropping an update when the channel is full is fine, the next one has the total
+            let _ = progress.try_send(Progress::Retries(self.id, retries));
+        };
+
+        // Only a few pages are held in memory at once, the client's rate limiter decides how fast
+        // they go out
+        let requests: Vec<_> = page_requests
+            .iter()
+            .map(|(comic, offset, page)| async move {
+                let (comic, offset) = (*comic, *offset);
+                let page_number = page.index();
+                if let Some(cache) = cache {
+                    if let Some((image_type, bytes)) = cache.get(comic, page_number).await {
+                        return Ok((offset + page_number, image_type, bytes));
+                    }
+                }
+
+                let (page_number, page) = page.into_async(client, &on_retry).await?;
+                let (image_type, bytes) = page.stream(client, &on_retry).await?;
+
+                if let Some(cache) = cache {
+                    cache.put(comic, page_number, image_type, &bytes).await?;
+                }
+
+                Ok::<_, KodanshaError>((offset + page_number, image_type, bytes))
+            })
+            .collect();
+        // Pages that finish early wait for the ones before them, so the sinks get them in
+        // reading order while no more than PAGES_IN_FLIGHT are held
+        let mut pages = stream::iter(requests).buffered(PAGES_IN_FLIGHT);
+
+        let mut done = 0;
+        while let Some(page) = pages.next().await {
+            let (page_number, image_type, bytes) = page?;
+
+            for sink in sinks.iter_mut() {
+                sink.add_page(page_number, &bytes, image_type.mime())?;
+            }
+
+            done += 1;
+            let decimal: f32 = done as f32 / page_count as f32;
+            let percent = decimal * 100.0f32;
+
+            // Nobody listening for progress isn't a reason to stop the download
+            let _ = progress.send(Progress::Pages(self.id, percent as u8)).await;
+        }
+
+        for sink in sinks.iter_mut() {
+            sink.finish()?;
+        }
+
+        Ok(page_count)
+    }
+
+    pub async fn page_links(&self, client: &KodanshaClient) -> Result<Vec<RemotePage>> {
+        page_links(client, self.id).await
     }
 }
+
+/// The pages of a volume or chapter
+async fn page_links(client: &KodanshaClient, comic: u16) -> Result<Vec<RemotePage>> {
+    let volume_route = format!("comic/{}/pages", comic);
+
+    client.get_json::<Vec<RemotePage>>(&volume_route).await
+}
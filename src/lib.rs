@@ -1,3 +1,4 @@
+pub mod cli;
 pub mod kodansha;
 pub mod tui;
 pub mod utils;
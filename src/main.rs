@@ -9,11 +9,22 @@ use crossterm::{
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use k_download::tui::App;
+use k_download::{cli::Options, kodansha::Settings, tui::App};
 use ratatui::{backend::Backend, backend::CrosstermBackend, Terminal};
+use structopt::StructOpt;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let options = Options::from_args();
+    let settings = options.settings()?;
+
+    match options.command {
+        Some(command) => command.run(settings).await,
+        None => tui(settings).await,
+    }
+}
+
+async fn tui(settings: Settings) -> Result<()> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
@@ -21,7 +32,7 @@ async fn main() -> Result<()> {
     let mut terminal = Terminal::new(backend)?;
 
     let tick_rate = Duration::from_millis(250);
-    let ret = run_app(&mut terminal, tick_rate).await;
+    let ret = run_app(&mut terminal, tick_rate, settings).await;
 
     disable_raw_mode()?;
     execute!(
@@ -36,7 +47,11 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn run_app<B>(terminal: &mut Terminal<B>, tick_rate: Duration) -> Result<()>
+async fn run_app<B>(
+    terminal: &mut Terminal<B>,
+    tick_rate: Duration,
+    settings: Settings,
+) -> Result<()>
 where
     B: Backend,
 {
@@ -44,7 +59,7 @@ where
 
     let mut normal_mode = true;
 
-    let mut app = App::default();
+    let mut app = App::new(settings);
 
     terminal.clear()?;
 
@@ -53,7 +68,7 @@ where
             .checked_sub(last_tick.elapsed())
             .unwrap_or_else(|| Duration::from_secs(0));
 
-        app.prerender().await?;
+        app.prerender().await;
         terminal.draw(|frame| app.render(frame))?;
 
         if crossterm::event::poll(timeout)? {
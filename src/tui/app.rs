@@ -1,43 +1,64 @@
-use anyhow::Ok;
 use crossterm::event::KeyEvent;
-use ratatui::{backend::Backend, Frame};
+use ratatui::{
+    backend::Backend,
+    layout::Rect,
+    style::{Color, Style},
+    widgets::Paragraph,
+    Frame,
+};
 
-use crate::Credentials;
+use crate::{kodansha::Settings, Credentials};
 
-use super::login::LoginScreen;
+use super::{login::LoginScreen, user::User as UserScreen};
 
 pub struct App {
     state: State,
+    settings: Settings,
+    /// The last error, shown at the bottom until the next key press
+    message: Option<String>,
+    tried_config: bool,
 }
 
 enum State {
-    User(super::user::User),
+    User(Box<UserScreen>),
     NoUser(LoginScreen),
 }
 
-impl Default for App {
-    fn default() -> App {
+impl App {
+    pub fn new(settings: Settings) -> App {
         App {
             state: State::NoUser(LoginScreen::default()),
+            settings,
+            message: None,
+            tried_config: false,
         }
     }
-}
 
-impl App {
-    pub async fn prerender(&mut self) -> anyhow::Result<()> {
+    pub async fn prerender(&mut self) {
+        if let Err(err) = self.try_prerender().await {
+            self.message = Some(err.to_string());
+        }
+    }
+
+    async fn try_prerender(&mut self) -> anyhow::Result<()> {
         match &mut self.state {
-            State::NoUser(login_screen) => match Credentials::from_config().await {
-                Result::Ok(credentials) => {
-                    let mut user = credentials.login().await?;
+            State::NoUser(login_screen) => {
+                // The stored credentials are only tried once, so a failing login isn't retried every tick
+                let credentials = match login_screen.get_credentials() {
+                    Some(credentials) => Some(credentials),
+                    None if !self.tried_config => {
+                        self.tried_config = true;
+                        Credentials::from_config().await.ok()
+                    }
+                    None => None,
+                };
+
+                if let Some(credentials) = credentials {
+                    let mut user = credentials.login(self.settings.client()?).await?;
                     user.load_library().await?;
-                    self.state = State::User(user.into());
-                }
-                _ => {
-                    if let Some(credentials) = login_screen.get_credentials() {
-                        self.state = State::User(credentials.login().await?.into())
-                    };
+                    self.state = State::User(Box::new(UserScreen::new(user, &self.settings)?));
                 }
-            },
+            }
             State::User(user_screen) => user_screen.prerender().await?,
         }
 
@@ -52,9 +73,19 @@ impl App {
             State::NoUser(login_screen) => login_screen.render(frame),
             State::User(user) => user.render(frame),
         }
+
+        if let Some(message) = &self.message {
+            let size = frame.size();
+            let line = Rect::new(size.x, size.bottom().saturating_sub(1), size.width, 1);
+            let message = Paragraph::new(message.clone()).style(Style::default().fg(Color::Red));
+
+            frame.render_widget(message, line);
+        }
     }
 
     pub fn new_event(&mut self, normal_mode: &mut bool, event: KeyEvent) -> bool {
+        self.message = None;
+
         match &mut self.state {
             State::NoUser(login_screen) => login_screen.new_event(normal_mode, event),
             State::User(user) => user.new_event(normal_mode, event),
@@ -43,10 +43,12 @@ impl LoginScreen {
         frame.render_widget(text_field, chunks[1]);
     }
 
-    pub fn get_credentials(&self) -> Option<Credentials> {
+    /// The submitted credentials, handed out once per submit
+    pub fn get_credentials(&mut self) -> Option<Credentials> {
         if !self.submit {
             return None;
         }
+        self.submit = false;
         let username = self.username.clone();
         let password = self.password.clone();
 
@@ -29,7 +29,7 @@ impl Tree {
         self.children = children;
     }
 
-    pub fn list_items(&self) -> Option<Vec<(ListItem, PathBuf)>> {
+    pub fn list_items(&self) -> Option<Vec<(ListItem<'_>, PathBuf)>> {
         if !self.path.is_dir() {
             return None;
         }
@@ -2,7 +2,7 @@ use std::{
     collections::HashMap,
     env::current_dir,
     fs,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
@@ -16,20 +16,19 @@ use ratatui::{
     Frame,
 };
 use tokio::{
-    fs::{try_exists, File},
-    io::AsyncWriteExt,
     sync::{
         mpsc::{channel, Receiver, Sender},
         Semaphore,
     },
-    task::JoinSet,
-    time::{sleep, Duration},
+    task::JoinHandle,
 };
 
 use crate::{
     kodansha::{
         user::{download_dir, set_download_dir},
-        Library,
+        Chapter, Choice, DownloadControl, EpubOptions, ExistingFiles, KodanshaClient, Library,
+        Manifest, OutputFormat, PageCache, PathTemplate, Progress, Queue, QueueState, SaveOptions,
+        Saved, Settings, Ticket,
     },
     tui::tree::Tree,
     User, Volume,
@@ -37,12 +36,41 @@ use crate::{
 
 pub struct Download {
     mode: Mode,
+    format: OutputFormat,
+    existing: ExistingFiles,
+    epub: EpubOptions,
+    template: Option<PathTemplate>,
+    /// Saves queued chapters that follow each other as one book
+    merge_chapters: bool,
     destination: DownloadDestination,
     library: Arc<Mutex<Option<Library>>>,
-    selected: Arc<Mutex<Vec<usize>>>,
+    queue: Arc<Mutex<Queue>>,
+    /// An error from where there was none to return, like a failed save, shown on the next tick
+    error: Arc<Mutex<Option<String>>>,
+    manifest: Manifest,
+    /// By queued id, the chapters of a merged run share theirs
+    running: HashMap<u16, Arc<Running>>,
+    /// Only bounds how many volumes download at once, the client paces the requests
+    semaphore: Arc<Semaphore>,
     percents: HashMap<u16, u8>,
-    tx: Sender<(u16, u8)>,
-    rx: Receiver<(u16, u8)>,
+    retries: HashMap<u16, u32>,
+    tx: Sender<Progress>,
+    rx: Receiver<Progress>,
+}
+
+/// A volume that's downloading, or waiting for its turn to
+struct Running {
+    control: DownloadControl,
+    handle: JoinHandle<()>,
+    /// What the progress is reported under, the first chapter's id for merged chapters
+    progress_id: u16,
+}
+
+/// What one download saves, and the queued items it finishes
+struct Job {
+    tickets: Vec<Ticket>,
+    volume: Volume,
+    comics: Vec<u16>,
 }
 
 #[derive(Debug)]
@@ -59,25 +87,59 @@ enum Mode {
     Normal,
     Download,
     DestinationSelection((Tree, ListState)),
+    /// Moving through the queue to pause or cancel volumes
+    Highlight(ListState),
 }
 
 impl Download {
-    pub fn new(library: Arc<Mutex<Option<Library>>>, selected: Arc<Mutex<Vec<usize>>>) -> Self {
+    /// Picks up the queue where the last run, or the CLI, left it
+    pub fn new(library: Arc<Mutex<Option<Library>>>, settings: &Settings) -> anyhow::Result<Self> {
         let (tx, rx) = channel(100);
-        Download {
+        Ok(Download {
             mode: Mode::default(),
+            format: settings.format,
+            existing: settings.existing,
+            epub: settings.epub.clone(),
+            template: settings.path_template.clone(),
+            merge_chapters: false,
             destination: DownloadDestination::None,
             library,
-            selected,
+            queue: Arc::new(Mutex::new(Queue::load()?)),
+            error: Arc::default(),
+            manifest: Manifest::load()?,
+            running: HashMap::default(),
+            semaphore: Arc::new(Semaphore::new(3)),
             tx,
             rx,
             percents: HashMap::default(),
-        }
+            retries: HashMap::default(),
+        })
     }
 
     pub async fn prerender(&mut self, user: &mut User) -> anyhow::Result<()> {
-        while let Ok((id, percent)) = self.rx.try_recv() {
-            self.percents.insert(id, percent);
+        self.running
+            .retain(|_, running| !running.handle.is_finished());
+
+        // The CLI may have queued or downloaded volumes since the last tick
+        self.queue.lock().unwrap().refresh()?;
+        self.manifest.refresh()?;
+
+        if let Some(err) = self.error.lock().unwrap().take() {
+            return Err(anyhow::anyhow!(err));
+        }
+
+        while let Ok(progress) = self.rx.try_recv() {
+            match progress {
+                Progress::Pages(id, percent) => {
+                    if percent == 0 {
+                        self.retries.remove(&id);
+                    }
+                    self.percents.insert(id, percent);
+                }
+                Progress::Retries(id, retries) => {
+                    self.retries.insert(id, retries);
+                }
+            }
         }
 
         match &mut self.destination {
@@ -145,90 +207,103 @@ impl Download {
             }
 
             (Mode::Download, DownloadDestination::Current(download_path)) => {
-                let selected_items: Vec<Volume> = {
+                // Anything that can fail comes before claiming, or the claimed volumes would be
+                // stuck in progress
+                let client = user.client().await?;
+                let options = Arc::new(SaveOptions {
+                    destination: download_path.clone(),
+                    formats: vec![self.format],
+                    existing: self.existing,
+                    epub: self.epub.clone(),
+                    template: self.template.clone(),
+                    cache: Some(PageCache::new()?),
+                    manifest: Some(self.manifest.clone()),
+                });
+
+                // Claimed up front, so pressing D again doesn't start them twice
+                let jobs: Vec<Job> = {
                     let library = self.library.lock().unwrap();
-                    let selected = self.selected.lock().unwrap();
+                    let library = library.clone().unwrap_or_default();
+                    let claimed = self.queue.lock().unwrap().update(|queue| {
+                        let mut claimed = Vec::new();
+                        for id in queue.waiting() {
+                            let Some(ticket) = queue.start(id) else {
+                                continue;
+                            };
 
-                    library
-                        .clone()
-                        .unwrap_or_default()
-                        .volumes
-                        .iter()
-                        .enumerate()
-                        .filter_map(|(index, volume)| {
-                            if selected.contains(&index) {
-                                Some(volume.clone())
-                            } else {
-                                None
+                            match library.find(id) {
+                                Some(_) => claimed.push(ticket),
+                                None => {
+                                    queue.finish(ticket, Err("Not in your library".to_string()));
+                                }
                             }
-                        })
-                        .collect()
-                };
-
-                let selectected_arc = Arc::new(Mutex::new(selected_items.clone()));
-                let selected = self.selected.clone();
-                let token = user.token().await?;
-                let tx = self.tx.clone();
-
-                let download_path = download_path.clone();
-                tokio::spawn(async move {
-                    let semaphore = Arc::new(Semaphore::new(3));
-                    let mut set = JoinSet::new();
-
-                    for (count, volume) in selected_items.iter().enumerate() {
-                        let mut download_path = download_path.clone();
-                        download_path.push(volume.volume_name.clone());
-                        download_path.set_extension("epub");
-
-                        let volume = volume.clone();
-                        let token = token.clone();
-                        let selected_items = selectected_arc.clone();
+                        }
 
-                        let tx = tx.clone();
-                        let selected = selected.clone();
+                        claimed
+                    })?;
 
-                        let permit = semaphore.clone().acquire_owned().await.unwrap();
-
-                        set.spawn(async move {
-                            let _ = tx.send((volume.id, 0)).await;
-
-                            let file = if try_exists(&download_path).await.unwrap_or(false) {
-                                File::open(download_path).await
-                            } else {
-                                File::create(download_path).await
-                            };
+                    jobs(&library, claimed, self.merge_chapters)
+                };
 
-                            if let Ok(mut file) = file {
-                                let mut buffer: Vec<u8> = vec![];
-                                sleep(Duration::from_millis(10 * count as u64)).await;
-                                if volume.write_epub_to(&token, &mut buffer, tx).await.is_ok() {
-                                    let _ = file.write_all(&buffer).await.is_ok();
-                                }
+                for job in jobs {
+                    let control = DownloadControl::new();
+                    let client = client.controlled(control.clone());
+                    let options = options.clone();
+                    let semaphore = self.semaphore.clone();
+                    let queue = self.queue.clone();
+                    let error = self.error.clone();
+                    let tx = self.tx.clone();
+                    let ids: Vec<u16> = job.tickets.iter().map(|ticket| ticket.id).collect();
+                    let progress_id = job.volume.id;
+
+                    let task_control = control.clone();
+                    let handle = tokio::spawn(async move {
+                        let Job {
+                            tickets,
+                            volume,
+                            comics,
+                        } = job;
+
+                        // Dropping the download when it's cancelled removes its partial files
+                        let result = tokio::select! {
+                            result = download(&volume, &comics, &options, &client, &semaphore, tx) => Some(result),
+                            _ = task_control.cancelled() => None,
+                        };
 
-                                if let Ok(mut selected_items) = selected_items.lock() {
-                                    let index = selected_items
-                                        .iter()
-                                        .enumerate()
-                                        .find_map(|(index, vol)| {
-                                            if vol.id == volume.id {
-                                                Some(index)
-                                            } else {
-                                                None
-                                            }
-                                        })
-                                        .unwrap();
-
-                                    selected_items.remove(index);
-                                    selected.lock().unwrap().remove(index);
+                        if result.is_none() {
+                            if let Some(cache) = &options.cache {
+                                for comic in &comics {
+                                    let _ = cache.remove(*comic).await;
                                 }
                             }
-
-                            drop(permit);
+                        }
+
+                        // The ticket keeps a late result from overwriting a newer download of the volume
+                        let saved = queue.lock().unwrap().update(|queue| {
+                            for ticket in tickets {
+                                match &result {
+                                    Some(Ok(Some(path))) => queue.skip(ticket, path.clone()),
+                                    Some(Ok(None)) => queue.finish(ticket, Ok(())),
+                                    Some(Err(reason)) => queue.finish(ticket, Err(reason.clone())),
+                                    None => queue.cancel(ticket),
+                                };
+                            }
                         });
+                        if let Err(err) = saved {
+                            *error.lock().unwrap() =
+                                Some(format!("Couldn't save the queue: {}", err));
+                        }
+                    });
+
+                    let running = Arc::new(Running {
+                        control,
+                        handle,
+                        progress_id,
+                    });
+                    for id in ids {
+                        self.running.insert(id, running.clone());
                     }
-
-                    while set.join_next().await.is_some() {}
-                });
+                }
 
                 self.mode = Mode::Normal;
             }
@@ -274,70 +349,146 @@ impl Download {
                     frame.render_stateful_widget(selection, rect, state);
                 }
             }
-            Mode::Download | Mode::Normal => {
+            Mode::Download | Mode::Normal | Mode::Highlight(_) => {
                 let chunks = Layout::default()
                     .direction(Direction::Vertical)
                     .constraints([Constraint::Length(1), Constraint::Max(3)])
                     .split(rect);
 
-                let selected_items: Vec<ListItem> = {
+                let queue = self.queue.lock().unwrap();
+
+                let queued_items: Vec<ListItem> = {
                     let library = self.library.lock().unwrap();
-                    let selected = self.selected.lock().unwrap();
+                    let library = library.clone().unwrap_or_default();
                     let styled = Style::default();
                     let percent_style = Style::default().fg(Color::Green);
-
-                    library
+                    let failed_style = Style::default().fg(Color::Red);
+                    let done_style = Style::default().fg(Color::Yellow);
+                    let paused_style = Style::default().fg(Color::Blue);
+                    let path_style = Style::default().fg(Color::DarkGray);
+                    let template = self
+                        .template
                         .clone()
-                        .unwrap_or_default()
-                        .volumes
+                        .unwrap_or_else(|| PathTemplate::default_for(self.format));
+                    let runs = match self.merge_chapters {
+                        true => library.chapter_runs(&queue.waiting()),
+                        false => Vec::new(),
+                    };
+
+                    queue
+                        .items()
                         .iter()
-                        .enumerate()
-                        .filter_map(|(index, volume)| {
-                            if selected.contains(&index) {
-                                Some(volume.clone())
-                            } else {
-                                None
-                            }
-                        })
-                        .map(|volume| {
-                            let percent = self
-                                .percents
-                                .get(&volume.id)
-                                .map(|percent| {
-                                    Span::styled(format!("[{}%] ", percent), percent_style)
+                        .map(|item| {
+                            let progress_id = self
+                                .running
+                                .get(&item.id)
+                                .map_or(item.id, |running| running.progress_id);
+
+                            let status = match &item.state {
+                                QueueState::Pending => Span::raw(""),
+                                QueueState::InProgress if self.is_paused(item.id) => {
+                                    let percent = self.percents.get(&progress_id).unwrap_or(&0);
+                                    Span::styled(format!("[paused {}%] ", percent), paused_style)
+                                }
+                                QueueState::InProgress => self
+                                    .percents
+                                    .get(&progress_id)
+                                    .map(|percent| {
+                                        let retries = match self.retries.get(&progress_id) {
+                                            Some(retries) => format!(", {} retries", retries),
+                                            None => String::new(),
+                                        };
+
+                                        Span::styled(
+                                            format!("[{}%{}] ", percent, retries),
+                                            percent_style,
+                                        )
+                                    })
+                                    .unwrap_or(Span::raw("")),
+                                QueueState::Done => Span::styled("[done] ", done_style),
+                                QueueState::Skipped(_) => {
+                                    Span::styled("[already there] ", done_style)
+                                }
+                                QueueState::Failed(reason) => {
+                                    Span::styled(format!("[{}] ", reason), failed_style)
+                                }
+                            };
+
+                            let comic = library.find(item.id);
+                            let name = comic
+                                .as_ref()
+                                .map(|comic| comic.name())
+                                .unwrap_or(format!("Volume {}", item.id));
+                            let span = Span::styled(name, styled);
+
+                            // Merged chapters are saved as their run
+                            let volume = runs
+                                .iter()
+                                .find(|run| run.iter().any(|chapter| chapter.id == item.id))
+                                .and_then(|run| Chapter::merge(run))
+                                .or_else(|| comic.map(|comic| comic.as_volume()));
+
+                            // Where it'll be saved, without the series the download fetches
+                            let path = volume
+                                .map(|volume| {
+                                    let path = template.render(&volume, None, self.format);
+                                    Span::styled(format!(" -> {}", path.display()), path_style)
                                 })
                                 .unwrap_or(Span::raw(""));
 
-                            let span = Span::styled(volume.volume_name, styled);
-
-                            let book = Spans::from(vec![percent, span]);
+                            let book = Spans::from(vec![status, span, path]);
 
                             ListItem::new(book)
                         })
                         .collect()
                 };
 
-                let download_title = format!(
-                    "Queue{}",
-                    if !selected_items.is_empty() {
-                        " (D)"
-                    } else {
-                        ""
+                let download_title = match self.mode {
+                    Mode::Highlight(_) => {
+                        "Queue - pause (P) - cancel (X), shift for the whole queue".to_string()
                     }
-                );
+                    _ => format!(
+                        "Queue (U){}{}",
+                        if !queue.waiting().is_empty() {
+                            " - download (D)"
+                        } else {
+                            ""
+                        },
+                        if queue.items().iter().any(|item| item.state.is_done()) {
+                            " - clear done (C)"
+                        } else {
+                            ""
+                        }
+                    ),
+                };
+
+                drop(queue);
 
                 let block = Block::default().title(download_title).borders(Borders::ALL);
 
                 let highlight_style = Style::default().add_modifier(Modifier::BOLD);
-                let selection = List::new(selected_items)
+                let selection = List::new(queued_items)
                     .block(block)
                     .highlight_style(highlight_style)
                     .highlight_symbol("> ");
 
-                frame.render_widget(selection, chunks[0]);
+                match &mut self.mode {
+                    Mode::Highlight(state) => {
+                        frame.render_stateful_widget(selection, chunks[0], state)
+                    }
+                    _ => frame.render_widget(selection, chunks[0]),
+                }
 
                 let block = Block::default()
-                    .title("Destination (F)")
+                    .title(format!(
+                        "Destination (F) - {} (T) - {} existing (E) - {} chapters (M)",
+                        self.format,
+                        self.existing,
+                        match self.merge_chapters {
+                            true => "merge",
+                            false => "separate",
+                        }
+                    ))
                     .borders(Borders::ALL);
 
                 let text = Span::raw(match &self.destination {
@@ -364,9 +515,127 @@ impl Download {
                 true
             }
 
+            (Mode::Normal, KeyCode::Char('t')) => {
+                self.format = self.format.next();
+
+                true
+            }
+
+            (Mode::Normal, KeyCode::Char('e')) => {
+                self.existing = self.existing.next();
+
+                true
+            }
+
+            (Mode::Normal, KeyCode::Char('m')) => {
+                self.merge_chapters = !self.merge_chapters;
+
+                true
+            }
+
+            (Mode::Normal, KeyCode::Char('u')) => {
+                let mut state = ListState::default();
+                if !self.queue.lock().unwrap().items().is_empty() {
+                    state.select(Some(0));
+                }
+
+                self.mode = Mode::Highlight(state);
+                *normal_mode = false;
+
+                true
+            }
+
+            (Mode::Highlight(state), KeyCode::Char('j') | KeyCode::Down) => {
+                let count = self.queue.lock().unwrap().items().len();
+                if count > 0 {
+                    let selected = state.selected().map_or(0, |selected| selected + 1);
+                    state.select(Some(selected % count));
+                }
+
+                true
+            }
+
+            (Mode::Highlight(state), KeyCode::Char('k') | KeyCode::Up) => {
+                let count = self.queue.lock().unwrap().items().len();
+                if count > 0 {
+                    let selected = match state.selected() {
+                        Some(selected) if selected >= 1 => selected - 1,
+                        _ => count - 1,
+                    };
+                    state.select(Some(selected));
+                }
+
+                true
+            }
+
+            (Mode::Highlight(state), KeyCode::Char('p')) => {
+                let id = queued_id(&self.queue, state.selected());
+                if let Some(running) = id.and_then(|id| self.running.get(&id)) {
+                    match running.control.is_paused() {
+                        true => running.control.resume(),
+                        false => running.control.pause(),
+                    }
+                }
+
+                true
+            }
+
+            (Mode::Highlight(_), KeyCode::Char('P')) => {
+                // Resumes everything if anything's paused, so one key press brings the queue back
+                let paused = self
+                    .running
+                    .values()
+                    .any(|running| running.control.is_paused());
+
+                for running in self.running.values() {
+                    match paused {
+                        true => running.control.resume(),
+                        false => running.control.pause(),
+                    }
+                }
+
+                true
+            }
+
+            (Mode::Highlight(state), KeyCode::Char('x')) => {
+                let id = queued_id(&self.queue, state.selected());
+                if let Some(running) = id.and_then(|id| self.running.get(&id)) {
+                    running.control.cancel();
+                }
+
+                true
+            }
+
+            (Mode::Highlight(_), KeyCode::Char('X')) => {
+                for running in self.running.values() {
+                    running.control.cancel();
+                }
+
+                true
+            }
+
+            (
+                Mode::Highlight(_) | Mode::DestinationSelection(_),
+                KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('l') | KeyCode::Char('u'),
+            ) => {
+                self.mode = Mode::Normal;
+                *normal_mode = true;
+
+                true
+            }
+
+            (Mode::Normal, KeyCode::Char('c')) => {
+                let saved = self.queue.lock().unwrap().update(Queue::clear_done);
+                if let Err(err) = saved {
+                    self.report(format!("Couldn't save the queue: {}", err));
+                }
+
+                true
+            }
+
             (Mode::Normal, KeyCode::Char('d')) => {
-                let selected = { self.selected.lock().unwrap().len() > 0 };
-                if let (DownloadDestination::Current(_), true) = (&mut self.destination, selected) {
+                let waiting = { !self.queue.lock().unwrap().waiting().is_empty() };
+                if let (DownloadDestination::Current(_), true) = (&mut self.destination, waiting) {
                     self.mode = Mode::Download;
 
                     true
@@ -460,7 +729,67 @@ impl Download {
         }
     }
 
-    pub fn get_selections(&self) -> Arc<Mutex<Vec<usize>>> {
-        self.selected.clone()
+    fn is_paused(&self, id: u16) -> bool {
+        self.running
+            .get(&id)
+            .is_some_and(|running| running.control.is_paused())
+    }
+
+    /// Shows the error on the next tick, for key presses that can't return one
+    pub fn report(&self, error: String) {
+        *self.error.lock().unwrap() = Some(error);
+    }
+
+    pub fn get_queue(&self) -> Arc<Mutex<Queue>> {
+        self.queue.clone()
+    }
+
+    pub fn get_manifest(&self) -> Manifest {
+        self.manifest.clone()
+    }
+}
+
+fn queued_id(queue: &Mutex<Queue>, index: Option<usize>) -> Option<u16> {
+    let queue = queue.lock().unwrap();
+
+    Some(queue.items().get(index?)?.id)
+}
+
+/// One download per book, finishing the tickets of every comic in it
+fn jobs(library: &Library, claimed: Vec<Ticket>, merge_chapters: bool) -> Vec<Job> {
+    let ids: Vec<u16> = claimed.iter().map(|ticket| ticket.id).collect();
+
+    library
+        .books(&ids, merge_chapters)
+        .into_iter()
+        .map(|(volume, comics)| Job {
+            tickets: claimed
+                .iter()
+                .filter(|ticket| comics.contains(&ticket.id))
+                .copied()
+                .collect(),
+            volume,
+            comics,
+        })
+        .collect()
+}
+
+/// Waits for a free slot, then downloads the volume.
+///
+/// Returns where the volume already was when it was skipped.
+async fn download(
+    volume: &Volume,
+    comics: &[u16],
+    options: &SaveOptions,
+    client: &KodanshaClient,
+    semaphore: &Semaphore,
+    tx: Sender<Progress>,
+) -> Result<Option<PathBuf>, String> {
+    let _permit = semaphore.acquire().await;
+    let _ = tx.send(Progress::Pages(volume.id, 0)).await;
+
+    match volume.save_comics(comics, options, client, tx).await {
+        Ok(saved) => Ok(Saved::all_skipped(&saved).map(Path::to_path_buf)),
+        Err(err) => Err(err.to_string()),
     }
 }
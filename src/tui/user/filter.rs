@@ -0,0 +1,93 @@
+use crate::kodansha::{Comic, Manifest, Series};
+
+/// What the library panel is narrowed down to
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    /// Matched fuzzily against the series and the volume or chapter name
+    pub query: String,
+    pub not_downloaded: bool,
+    pub genre: Option<String>,
+    pub series: Option<u16>,
+}
+
+impl Filter {
+    pub fn is_active(&self) -> bool {
+        !self.query.is_empty()
+            || self.not_downloaded
+            || self.genre.is_some()
+            || self.series.is_some()
+    }
+
+    pub fn matches(&self, comic: &Comic, manifest: &Manifest, series: &[Series]) -> bool {
+        if self.not_downloaded && manifest.is_downloaded(comic.id()) {
+            return false;
+        }
+
+        if self.series.is_some_and(|id| id != comic.series_id()) {
+            return false;
+        }
+
+        if let Some(genre) = &self.genre {
+            let has_genre = series
+                .iter()
+                .find(|series| series.id == comic.series_id())
+                .is_some_and(|series| series.genres.contains(genre));
+
+            if !has_genre {
+                return false;
+            }
+        }
+
+        // With the series, in case a volume's name leaves it out
+        let name = format!("{} {}", comic.series_name(), comic.name());
+        fuzzy(&self.query, &name)
+    }
+
+    /// The genre after the current one, and no genre after the last
+    pub fn next_genre(&mut self, series: &[Series]) {
+        let mut genres: Vec<&String> = series.iter().flat_map(|series| &series.genres).collect();
+        genres.sort();
+        genres.dedup();
+
+        let next = match &self.genre {
+            None => 0,
+            Some(genre) => match genres.iter().position(|other| *other == genre) {
+                Some(index) => index + 1,
+                None => 0,
+            },
+        };
+
+        self.genre = genres.get(next).map(|genre| genre.to_string());
+    }
+
+    /// Like "/query - not downloaded - genre: Action", for the panel's title
+    pub fn describe(&self, series_name: Option<&str>) -> String {
+        let mut parts = Vec::new();
+
+        if !self.query.is_empty() {
+            parts.push(format!("/{}", self.query));
+        }
+        if self.not_downloaded {
+            parts.push("not downloaded".to_string());
+        }
+        if let Some(genre) = &self.genre {
+            parts.push(format!("genre: {}", genre));
+        }
+        if let Some(series_name) = series_name {
+            parts.push(format!("series: {}", series_name));
+        }
+
+        parts.join(" - ")
+    }
+}
+
+/// Whether every character of the query shows up in the text, in order, ignoring case
+fn fuzzy(query: &str, text: &str) -> bool {
+    let mut text = text.chars().flat_map(char::to_lowercase);
+
+    query
+        .chars()
+        .flat_map(char::to_lowercase)
+        .filter(|character| !character.is_whitespace())
+        .all(|character| text.any(|other| other == character))
+}
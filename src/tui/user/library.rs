@@ -1,21 +1,37 @@
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 
 use crossterm::event::{KeyCode, KeyEvent};
 use ratatui::{
     backend::Backend,
     layout::{Constraint, Direction, Layout},
-    style::{Modifier, Style},
+    style::{Color, Modifier, Style},
     text::{Span, Spans},
     widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap},
     Frame,
 };
 
-use crate::utils::ToDedup;
+use crate::{
+    kodansha::{Choice, Comic, Manifest, Queue, Series, Settings, SortOrder},
+    utils::ToDedup,
+};
 
-use super::Download;
+use super::{
+    filter::Filter,
+    series::{self, Row},
+    Download,
+};
 
 pub struct User {
-    selected: Arc<Mutex<Vec<usize>>>,
+    queue: Arc<Mutex<Queue>>,
+    manifest: Manifest,
+    /// Series fetched in the background for their genres, empty until they're in
+    series: Arc<Mutex<Vec<Series>>>,
+    tried_series: bool,
+    /// Ids of the series that are expanded
+    open: HashSet<u16>,
+    filter: Filter,
+    sort: SortOrder,
 
     list_state: ListState,
     user: crate::User,
@@ -28,14 +44,74 @@ enum Mode {
     #[default]
     Normal,
     Highlight,
+    /// Typing the search query, with the library filtered as it's typed
+    Search,
     Download,
 }
 
 impl User {
     pub async fn prerender(&mut self) -> anyhow::Result<()> {
+        // The genres are only for the info panel and the genre filter, so the library is
+        // shown and usable while they're fetched, and a failing series isn't fetched every tick
+        if !self.tried_series {
+            self.tried_series = true;
+
+            let library = self.user.library();
+            let library = { library.lock().unwrap().clone() };
+            if let Some(library) = library {
+                let client = self.user.client().await?;
+                let series = self.series.clone();
+
+                tokio::spawn(async move {
+                    let fetched = Series::from_library(&library, &client).await;
+                    *series.lock().unwrap() = fetched;
+                });
+            }
+        }
+
         self.download_tab.prerender(&mut self.user).await
     }
 
+    /// The lines of the library panel, series with their volumes and chapters under them when open.
+    /// Sorts that mix up the series list the volumes and chapters on their own instead.
+    ///
+    /// Only what passes the filter is listed, with every series open so the matches show.
+    fn rows(&self) -> Vec<Row> {
+        let library = self.user.library();
+        let library = library.lock().unwrap();
+        let fetched = self.series.lock().unwrap();
+        let entries: Vec<Comic> = Option::as_ref(&library)
+            .map(|library| library.sorted(self.sort, &self.manifest))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|comic| self.filter.matches(comic, &self.manifest, &fetched))
+            .collect();
+
+        if !self.sort.groups_series() {
+            return entries.into_iter().map(Row::Comic).collect();
+        }
+
+        match self.filter.is_active() {
+            true => {
+                let open = entries.iter().map(|comic| comic.series_id()).collect();
+                series::rows(entries, &open)
+            }
+            false => series::rows(entries, &self.open),
+        }
+    }
+
+    /// Keeps the selection on the list after it's filtered
+    fn clamp_selection(&mut self) {
+        let count = self.rows().len();
+
+        match (self.list_state.selected(), count) {
+            (_, 0) => self.list_state.select(None),
+            (Some(selected), count) if selected >= count => self.list_state.select(Some(count - 1)),
+            (None, _) => self.list_state.select(Some(0)),
+            _ => (),
+        }
+    }
+
     pub fn render<B>(&mut self, frame: &mut Frame<B>)
     where
         B: Backend,
@@ -46,39 +122,105 @@ impl User {
             .split(frame.size());
 
         let styled = Style::default();
+        let downloaded_style = Style::default().fg(Color::Green);
 
-        let list_items: Vec<ListItem> = {
-            let selected = self.selected.lock().unwrap();
+        let rows = self.rows();
+        let grouped = self.sort.groups_series();
 
-            let library = self.user.library();
-            let library = library.lock().unwrap();
-            let library = library.clone().unwrap_or_default();
-
-            library
-                .volumes
-                .iter()
-                .enumerate()
-                .map(|(index, volume)| {
-                    let span = Span::styled(
-                        format!(
+        let list_items: Vec<ListItem> = {
+            let queue = self.queue.lock().unwrap();
+            let queued = |comic: &&Comic| {
+                queue
+                    .state(comic.id())
+                    .is_some_and(|state| !state.is_done())
+            };
+
+            rows.iter()
+                .map(|row| {
+                    let comics = row.comics();
+                    let downloaded = comics
+                        .iter()
+                        .filter(|comic| self.manifest.is_downloaded(comic.id()))
+                        .count();
+                    let style = match downloaded == comics.len() {
+                        true => downloaded_style,
+                        false => styled,
+                    };
+
+                    let text = match row {
+                        Row::Series {
+                            title,
+                            open,
+                            comics,
+                            ..
+                        } => format!(
+                            "{mark} {arrow} {title} ({downloaded}/{count})",
+                            mark = match comics.iter().filter(queued).count() {
+                                0 => "[ ]",
+                                count if count == comics.len() => "[x]",
+                                _ => "[-]",
+                            },
+                            arrow = match open {
+                                true => "▾",
+                                false => "▸",
+                            },
+                            title = title,
+                            downloaded = downloaded,
+                            count = comics.len()
+                        ),
+                        Row::Comic(comic) if !grouped => format!(
                             "{mark} {title}",
-                            mark = match selected.contains(&index) {
+                            mark = match queued(&comic) {
                                 true => "[x]",
                                 false => "[ ]",
                             },
-                            title = volume.volume_name
+                            title = comic.name()
                         ),
-                        styled,
-                    );
+                        Row::Comic(comic) => format!(
+                            "    {mark} {title}",
+                            mark = match queued(&comic) {
+                                true => "[x]",
+                                false => "[ ]",
+                            },
+                            title = match comic {
+                                Comic::Volume(volume) => volume.volume_name.clone(),
+                                Comic::Chapter(chapter) => chapter.name(),
+                            }
+                        ),
+                    };
 
-                    ListItem::new(span)
+                    ListItem::new(Span::styled(text, style))
                 })
                 .collect()
         };
 
         let highlight_style = Style::default().add_modifier(Modifier::BOLD);
 
-        let block = Block::default().title("Library (L)").borders(Borders::ALL);
+        let series_name = self.filter.series.and_then(|id| {
+            let library = self.user.library();
+            let library = library.lock().unwrap();
+            let comic = Option::as_ref(&library)?
+                .entries()
+                .into_iter()
+                .find(|comic| comic.series_id() == id)?;
+
+            Some(comic.series_name().to_string())
+        });
+        let filter = self.filter.describe(series_name.as_deref());
+
+        let title = match self.mode {
+            Mode::Search => format!("Library /{}", self.filter.query),
+            Mode::Highlight if self.filter.is_active() => {
+                format!("Library {} - clear (R) - by {} (Shift+S)", filter, self.sort)
+            }
+            Mode::Highlight => "Library - open (O) - queue (A) - search (/) - not downloaded (N) - genre (G) - series (S)".to_string(),
+            _ if self.filter.is_active() => {
+                format!("Library (L) {} - by {} (Shift+S)", filter, self.sort)
+            }
+            _ => format!("Library (L) - by {} (Shift+S)", self.sort),
+        };
+
+        let block = Block::default().title(title).borders(Borders::ALL);
         let list = List::new(list_items)
             .block(block)
             .highlight_style(highlight_style)
@@ -94,18 +236,46 @@ impl User {
 
         let block = Block::default().title("Book Info").borders(Borders::ALL);
 
-        let library = self.user.library();
-        let library = library.lock();
-        let text: Vec<Spans> = match library.ok().and_then(|library| {
-            library.clone().and_then(|library| {
-                self.list_state
-                    .selected()
-                    .and_then(|index| Some(library.volumes.get(index)?.clone()))
-            })
-        }) {
-            Some(volume) => {
+        let row = self.list_state.selected().and_then(|index| rows.get(index));
+        let genres = row
+            .and_then(|row| series::genres(&self.series.lock().unwrap(), row.series_id()))
+            .unwrap_or_default();
+
+        let text: Vec<Spans> = match row {
+            Some(Row::Series { title, comics, .. }) => {
+                let count = |matches: fn(&Comic) -> bool| {
+                    comics.iter().filter(|comic| matches(comic)).count()
+                };
+                let downloaded = comics
+                    .iter()
+                    .filter(|comic| self.manifest.is_downloaded(comic.id()))
+                    .count();
+
+                vec![
+                    Spans::from(vec![Span::raw("Series:")]),
+                    Spans::from(vec![Span::raw(title.clone())]),
+                    Spans::from(vec![Span::raw("")]),
+                    Spans::from(vec![Span::raw("Genres:")]),
+                    Spans::from(vec![Span::raw(genres)]),
+                    Spans::from(vec![Span::raw("")]),
+                    Spans::from(vec![Span::raw(format!(
+                        "Volumes: {}",
+                        count(|comic| matches!(comic, Comic::Volume(_)))
+                    ))]),
+                    Spans::from(vec![Span::raw(format!(
+                        "Chapters: {}",
+                        count(|comic| matches!(comic, Comic::Chapter(_)))
+                    ))]),
+                    Spans::from(vec![Span::raw(format!(
+                        "Downloaded: {} of {}",
+                        downloaded,
+                        comics.len()
+                    ))]),
+                ]
+            }
+            Some(Row::Comic(comic)) => {
                 let mut description = {
-                    let escaped = html_escape::decode_html_entities(volume.description.as_str());
+                    let escaped = html_escape::decode_html_entities(comic.description());
 
                     let description: Vec<Spans> = escaped
                         .replace('\r', "")
@@ -119,12 +289,20 @@ impl User {
                     description
                 };
 
+                let (kind, name) = match &comic {
+                    Comic::Volume(volume) => ("Volume:", volume.volume_name.clone()),
+                    Comic::Chapter(chapter) => ("Chapter:", chapter.name()),
+                };
+
                 let mut ret = vec![
                     Spans::from(vec![Span::raw("Series:")]),
-                    Spans::from(vec![Span::raw(volume.series_name)]),
+                    Spans::from(vec![Span::raw(comic.series_name().to_string())]),
                     Spans::from(vec![Span::raw("")]),
-                    Spans::from(vec![Span::raw("Volume:")]),
-                    Spans::from(vec![Span::raw(volume.volume_name)]),
+                    Spans::from(vec![Span::raw("Genres:")]),
+                    Spans::from(vec![Span::raw(genres)]),
+                    Spans::from(vec![Span::raw("")]),
+                    Spans::from(vec![Span::raw(kind)]),
+                    Spans::from(vec![Span::raw(name)]),
                     Spans::from(vec![Span::raw("")]),
                     Spans::from(vec![Span::raw("Description:")]),
                 ];
@@ -142,8 +320,7 @@ impl User {
     }
 
     pub fn new_event(&mut self, normal_mode: &mut bool, event: KeyEvent) -> bool {
-        let library = self.user.library();
-        let library = library.lock().unwrap();
+        let rows = self.rows();
         match (&mut self.mode, event.code) {
             (Mode::Normal, KeyCode::Char('l')) => {
                 self.list_state.select(Some(0));
@@ -152,7 +329,92 @@ impl User {
                 true
             }
 
-            (Mode::Normal, KeyCode::Char('d') | KeyCode::Char('f')) => {
+            (Mode::Normal | Mode::Highlight, KeyCode::Char('/')) => {
+                *normal_mode = false;
+                self.mode = Mode::Search;
+                self.clamp_selection();
+
+                true
+            }
+
+            (Mode::Search, KeyCode::Enter) => {
+                self.mode = Mode::Highlight;
+
+                true
+            }
+
+            (Mode::Search, KeyCode::Esc) => {
+                self.filter.query.clear();
+                self.mode = Mode::Highlight;
+                self.clamp_selection();
+
+                true
+            }
+
+            (Mode::Search, KeyCode::Backspace) => {
+                self.filter.query.pop();
+                self.clamp_selection();
+
+                true
+            }
+
+            (Mode::Search, KeyCode::Char(character)) => {
+                self.filter.query.push(character);
+                self.clamp_selection();
+
+                true
+            }
+
+            (Mode::Search, _) => true,
+
+            (Mode::Normal | Mode::Highlight, KeyCode::Char('S')) => {
+                self.sort = self.sort.next();
+                // The sort still changes for now when it can't be saved
+                if let Err(err) = Settings::remember_sort(self.sort) {
+                    self.download_tab
+                        .report(format!("Couldn't save the sort: {}", err));
+                }
+
+                true
+            }
+
+            (Mode::Highlight, KeyCode::Char('n')) => {
+                self.filter.not_downloaded = !self.filter.not_downloaded;
+                self.clamp_selection();
+
+                true
+            }
+
+            (Mode::Highlight, KeyCode::Char('g')) => {
+                self.filter.next_genre(&self.series.lock().unwrap());
+                self.clamp_selection();
+
+                true
+            }
+
+            (Mode::Highlight, KeyCode::Char('s')) => {
+                // Narrows down to the highlighted series, or back out of it
+                self.filter.series = match self.filter.series {
+                    Some(_) => None,
+                    None => self
+                        .list_state
+                        .selected()
+                        .and_then(|index| rows.get(index))
+                        .map(|row| row.series_id()),
+                };
+                self.clamp_selection();
+
+                true
+            }
+
+            (Mode::Highlight, KeyCode::Char('r')) => {
+                self.filter = Filter::default();
+                self.clamp_selection();
+
+                true
+            }
+
+            (Mode::Normal, KeyCode::Char('d') | KeyCode::Char('f') | KeyCode::Char('u')) => {
                 self.mode = Mode::Download;
                 self.download_tab.new_event(normal_mode, event);
 
@@ -167,27 +429,24 @@ impl User {
             }
 
             (Mode::Highlight, KeyCode::Char('j') | KeyCode::Down) => {
-                match (Option::as_ref(&library), self.list_state.selected()) {
-                    (Some(library), Some(selected)) => {
-                        let volumes = library.volumes.clone();
-                        let count = volumes.len();
+                match (rows.len(), self.list_state.selected()) {
+                    (0, _) => (),
+                    (count, Some(selected)) => {
                         let new_selection = selected + 1;
                         self.list_state.select(Some(new_selection % count));
                     }
-                    (Some(_), None) => {
+                    (_, None) => {
                         self.list_state.select(Some(0));
                     }
-                    _ => (),
                 };
 
                 true
             }
 
             (Mode::Highlight, KeyCode::Char('k') | KeyCode::Up) => {
-                match (Option::as_ref(&library), self.list_state.selected()) {
-                    (Some(library), Some(selected)) => {
-                        let volumes = library.volumes.clone();
-                        let count = volumes.len();
+                match (rows.len(), self.list_state.selected()) {
+                    (0, _) => (),
+                    (count, Some(selected)) => {
                         let new_selection = if selected >= 1 {
                             selected - 1
                         } else {
@@ -195,18 +454,25 @@ impl User {
                         };
                         self.list_state.select(Some(new_selection));
                     }
-                    (Some(_), None) => {
+                    (_, None) => {
                         self.list_state.select(Some(0));
                     }
-                    _ => (),
                 };
                 true
             }
 
+            // `u` again leaves the queue, like it leaves the destination selection
             (
-                Mode::Highlight | Mode::Download,
-                KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('l'),
+                Mode::Download,
+                KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('l') | KeyCode::Char('u'),
             ) => {
+                self.mode = Mode::Normal;
+                self.download_tab.new_event(normal_mode, event);
+
+                true
+            }
+
+            (Mode::Highlight, KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('l')) => {
                 self.list_state.select(None);
                 self.mode = Mode::Normal;
                 *normal_mode = true;
@@ -214,14 +480,42 @@ impl User {
                 true
             }
 
+            (Mode::Highlight, KeyCode::Char('o') | KeyCode::Enter) => {
+                let row = self.list_state.selected().and_then(|index| rows.get(index));
+
+                if let Some(row) = row {
+                    // Closing from a volume closes its series, and moves up to it
+                    let series_id = row.series_id();
+                    if !self.open.remove(&series_id) {
+                        self.open.insert(series_id);
+                    }
+
+                    let index = self
+                        .rows()
+                        .iter()
+                        .position(|row| matches!(row, Row::Series { id, .. } if *id == series_id));
+                    if let (Row::Comic(_), Some(index)) = (row, index) {
+                        self.list_state.select(Some(index));
+                    }
+                }
+
+                true
+            }
+
             (Mode::Highlight, KeyCode::Char(' ') | KeyCode::Char('a')) => {
-                let mut selected_item = self.selected.lock().unwrap();
-                if let Some(selected) = self.list_state.selected() {
-                    if selected_item.contains(&selected) {
-                        let index = selected_item.iter().position(|x| *x == selected).unwrap();
-                        selected_item.remove(index);
-                    } else {
-                        selected_item.push(selected);
+                let row = self.list_state.selected().and_then(|index| rows.get(index));
+
+                if let Some(row) = row {
+                    // Finished volumes are queued again, so they can be downloaded once more.
+                    // Downloading ones are left be, they're cancelled from the queue
+                    let saved = self
+                        .queue
+                        .lock()
+                        .unwrap()
+                        .update(|queue| series::toggle(queue, row));
+                    if let Err(err) = saved {
+                        self.download_tab
+                            .report(format!("Couldn't save the queue: {}", err));
                     }
                 }
 
@@ -233,20 +527,27 @@ impl User {
     }
 }
 
-impl From<crate::User> for User {
-    fn from(user: crate::User) -> Self {
+impl User {
+    pub fn new(user: crate::User, settings: &Settings) -> anyhow::Result<Self> {
         let list_state = ListState::default();
 
         let library = user.library();
-        let download_tab = Download::new(library, Arc::default());
-        let selected = download_tab.get_selections();
-
-        User {
-            selected,
+        let download_tab = Download::new(library, settings)?;
+        let queue = download_tab.get_queue();
+        let manifest = download_tab.get_manifest();
+
+        Ok(User {
+            queue,
+            manifest,
+            series: Arc::default(),
+            tried_series: false,
+            open: HashSet::new(),
+            filter: Filter::default(),
+            sort: settings.sort,
             list_state,
             user,
             mode: Mode::default(),
             download_tab,
-        }
+        })
     }
 }
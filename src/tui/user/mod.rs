@@ -1,5 +1,7 @@
 pub mod download;
+pub mod filter;
 pub mod library;
+pub mod series;
 
 pub use download::Download;
 pub use library::User;
@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+
+use crate::kodansha::{Comic, Queue, QueueState, Series};
+
+/// One line of the library panel
+#[derive(Debug, Clone)]
+pub enum Row {
+    /// A series, with every volume and chapter of it whether it's open or not
+    Series {
+        id: u16,
+        title: String,
+        comics: Vec<Comic>,
+        open: bool,
+    },
+    Comic(Comic),
+}
+
+/// Groups the comics by series, in the order each series first shows up, with
+/// the comics of the open series listed right under them
+pub fn rows(comics: Vec<Comic>, open: &HashSet<u16>) -> Vec<Row> {
+    let mut groups: Vec<(u16, String, Vec<Comic>)> = Vec::new();
+
+    for comic in comics {
+        match groups
+            .iter_mut()
+            .find(|(id, _, _)| *id == comic.series_id())
+        {
+            Some((_, _, comics)) => comics.push(comic),
+            None => groups.push((
+                comic.series_id(),
+                comic.series_name().to_string(),
+                vec![comic],
+            )),
+        }
+    }
+
+    let mut rows = Vec::new();
+    for (id, title, comics) in groups {
+        let is_open = open.contains(&id);
+
+        rows.push(Row::Series {
+            id,
+            title,
+            comics: comics.clone(),
+            open: is_open,
+        });
+
+        if is_open {
+            rows.extend(comics.into_iter().map(Row::Comic));
+        }
+    }
+
+    rows
+}
+
+impl Row {
+    /// The volumes and chapters the row stands for
+    pub fn comics(&self) -> Vec<&Comic> {
+        match self {
+            Row::Series { comics, .. } => comics.iter().collect(),
+            Row::Comic(comic) => vec![comic],
+        }
+    }
+
+    pub fn series_id(&self) -> u16 {
+        match self {
+            Row::Series { id, .. } => *id,
+            Row::Comic(comic) => comic.series_id(),
+        }
+    }
+}
+
+/// Queues every comic of the row, or takes them all off the queue when they're
+/// all queued already. Downloading ones are left be, like a single volume.
+pub fn toggle(queue: &mut Queue, row: &Row) {
+    let queued = |queue: &Queue, id: u16| queue.state(id).is_some_and(|state| !state.is_done());
+
+    let comics = row.comics();
+    let all_queued = comics.iter().all(|comic| queued(queue, comic.id()));
+
+    for comic in comics {
+        match (all_queued, queue.state(comic.id())) {
+            (_, Some(QueueState::InProgress)) => (),
+            (true, Some(_)) => queue.remove(comic.id()),
+            (false, None) => queue.add(comic.id()),
+            (false, Some(state)) if state.is_done() => queue.add(comic.id()),
+            _ => (),
+        }
+    }
+}
+
+/// The series' genres, if they were fetched
+pub fn genres(series: &[Series], id: u16) -> Option<String> {
+    let series = series.iter().find(|series| series.id == id)?;
+
+    Some(series.genres.join(", "))
+}
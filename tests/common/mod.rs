@@ -0,0 +1,119 @@
+//! A local stand-in for the Kodansha API, shared by the tests that save volumes.
+
+use std::{sync::Arc, time::Duration};
+
+use k_download::kodansha::{
+    EpubOptions, ExistingFiles, OutputFormat, RateLimit, RetryPolicy, Settings, SortOrder,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// The volume with id 1 the stand-in serves
+pub struct Api {
+    pub pages: usize,
+    /// Every page is the same image, so it's shared instead of allocated per request
+    pub image: Arc<Vec<u8>>,
+    /// How long to wait before answering with a page's image
+    pub delay: fn(u64) -> Duration,
+}
+
+/// Starts serving `api` in the background, and returns its url
+pub async fn serve(api: Api) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let api = Arc::new(api);
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(connection(stream, api.clone()));
+        }
+    });
+
+    format!("http://{}", address)
+}
+
+/// Settings pointed at the stand-in, without a rate limit
+pub fn test_settings(api_url: String) -> Settings {
+    Settings {
+        api_url,
+        connect_timeout_secs: 10,
+        timeout_secs: 60,
+        proxy: None,
+        retry: RetryPolicy::default(),
+        rate_limit: RateLimit {
+            requests_per_second: 0.0,
+            burst: 0,
+        },
+        format: OutputFormat::Epub,
+        existing: ExistingFiles::default(),
+        epub: EpubOptions::default(),
+        path_template: None,
+        sort: SortOrder::default(),
+    }
+}
+
+/// Just enough HTTP/1.1 to answer the routes a volume download uses
+async fn connection(mut stream: TcpStream, api: Arc<Api>) {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+
+    loop {
+        let head_end = request.windows(4).position(|window| window == b"\r\n\r\n");
+        let Some(head_end) = head_end else {
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => request.extend_from_slice(&buffer[..read]),
+            }
+            continue;
+        };
+
+        let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+        request.drain(..head_end + 4);
+
+        let path = head.split_whitespace().nth(1).unwrap_or_default();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        let (status, content_type, body) = match segments.as_slice() {
+            ["comic", "1", "pages"] => {
+                let pages: Vec<String> = (1..=api.pages)
+                    .map(|page| format!("{{\"pageNumber\":{},\"comicID\":1}}", page))
+                    .collect();
+
+                (
+                    "200 OK",
+                    "application/json",
+                    format!("[{}]", pages.join(",")).into_bytes(),
+                )
+            }
+            ["comic", "1", "pages", page] => (
+                "200 OK",
+                "application/json",
+                format!("{{\"url\":\"/images/{}\"}}", page).into_bytes(),
+            ),
+            ["images", page] => {
+                tokio::time::sleep((api.delay)(page.parse().unwrap_or_default())).await;
+
+                ("200 OK", "image/jpeg", Vec::new())
+            }
+            _ => ("404 Not Found", "text/plain", Vec::new()),
+        };
+
+        let body: &[u8] = match content_type {
+            "image/jpeg" => &api.image,
+            _ => &body,
+        };
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            status,
+            content_type,
+            body.len()
+        );
+
+        if stream.write_all(head.as_bytes()).await.is_err() || stream.write_all(body).await.is_err()
+        {
+            return;
+        }
+    }
+}
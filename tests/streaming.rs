@@ -0,0 +1,131 @@
+//! Downloads a volume far bigger than the memory it's allowed to use from a
+//! local stand-in for the Kodansha API, and checks the allocator's high-water mark.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs::File,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use k_download::kodansha::{EpubOptions, ExistingFiles, OutputFormat, SaveOptions, Saved, Volume};
+use tokio::sync::mpsc::channel;
+
+mod common;
+
+const PAGES: usize = 200;
+const PAGE_SIZE: usize = 512 * 1024;
+
+/// Keeps track of how much memory is allocated, and the most that ever was
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            grow(layout.size());
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            grow(new_size);
+        }
+
+        new_ptr
+    }
+}
+
+fn grow(size: usize) {
+    let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(allocated, Ordering::Relaxed);
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn large_volume_is_streamed_to_disk() {
+    let mut image = vec![0; PAGE_SIZE];
+    image[..4].copy_from_slice(&[0xFF, 0xD8, 0xFF, 0xE0]);
+
+    let api_url = common::serve(common::Api {
+        pages: PAGES,
+        image: Arc::new(image),
+        delay: |_| Duration::ZERO,
+    })
+    .await;
+
+    let settings = common::test_settings(api_url);
+    let client = settings.client().unwrap();
+
+    let volume = Volume {
+        series_name: "Synthetic".to_string(),
+        volume_name: "Synthetic 1".to_string(),
+        volume_number: 1,
+        page_count: PAGES as u16,
+        description: "A volume that doesn't fit in memory".to_string(),
+        id: 1,
+        series_id: 1,
+    };
+
+    let destination: PathBuf =
+        std::env::temp_dir().join(format!("k-download-streaming-{}", std::process::id()));
+    std::fs::create_dir_all(&destination).unwrap();
+
+    let (tx, mut rx) = channel(100);
+    tokio::spawn(async move { while rx.recv().await.is_some() {} });
+
+    let baseline = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+
+    let options = SaveOptions {
+        destination: destination.clone(),
+        formats: vec![OutputFormat::Epub],
+        existing: ExistingFiles::Overwrite,
+        epub: EpubOptions::default(),
+        template: None,
+        cache: None,
+        manifest: None,
+    };
+    let saved = volume.save(&options, &client, tx).await.unwrap();
+
+    let peak = PEAK.load(Ordering::Relaxed) - baseline;
+    let volume_size = PAGES * PAGE_SIZE;
+
+    let path = match &saved[..] {
+        [Saved::Written(path)] => path,
+        saved => panic!("expected one written volume, got {:?}", saved),
+    };
+    let archive = zip::ZipArchive::new(File::open(path).unwrap()).unwrap();
+    let images = archive
+        .file_names()
+        .filter(|name| name.starts_with("OEBPS/images/"))
+        .count();
+
+    std::fs::remove_dir_all(&destination).unwrap();
+
+    assert_eq!(images, PAGES);
+    assert!(
+        peak < volume_size / 4,
+        "peak memory was {} bytes for a {} byte volume",
+        peak,
+        volume_size
+    );
+}
@@ -0,0 +1,95 @@
+//! Saves a volume through a path template that puts it in folders which don't
+//! exist yet, from a local stand-in for the Kodansha API that answers the last
+//! page first, and checks the CBZ has its pages in reading order anyway.
+
+use std::{fs::File, path::PathBuf, sync::Arc, time::Duration};
+
+use k_download::kodansha::{
+    Choice, EpubOptions, ExistingFiles, OutputFormat, PathTemplate, SaveOptions, Saved, Volume,
+};
+use tokio::sync::mpsc::channel;
+
+mod common;
+
+const PAGES: usize = 3;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nested_templates_create_their_folders() {
+    let api_url = common::serve(common::Api {
+        pages: PAGES,
+        image: Arc::new(vec![0xFF, 0xD8, 0xFF, 0xE0, 0, 0]),
+        // The last page comes back first
+        delay: |page| Duration::from_millis(50 * (PAGES as u64 - page)),
+    })
+    .await;
+
+    let settings = common::test_settings(api_url);
+    let client = settings.client().unwrap();
+
+    let volume = Volume {
+        series_name: "Synthetic".to_string(),
+        volume_name: "Synthetic: The Volume".to_string(),
+        volume_number: 3,
+        page_count: PAGES as u16,
+        description: "A volume saved a few folders down".to_string(),
+        id: 1,
+        series_id: 1,
+    };
+
+    let destination: PathBuf =
+        std::env::temp_dir().join(format!("k-download-templates-{}", std::process::id()));
+
+    let (tx, mut rx) = channel(100);
+    tokio::spawn(async move { while rx.recv().await.is_some() {} });
+
+    let template: PathTemplate = "{series}/{series} v{volume_number:02} - {volume_name}.{ext}"
+        .parse()
+        .unwrap();
+    let options = SaveOptions {
+        destination: destination.clone(),
+        formats: OutputFormat::ALL.to_vec(),
+        existing: ExistingFiles::Overwrite,
+        epub: EpubOptions::default(),
+        template: Some(template),
+        cache: None,
+        manifest: None,
+    };
+    let saved = volume.save(&options, &client, tx).await;
+
+    let expected: Vec<Saved> = ["epub", "cbz", "images"]
+        .into_iter()
+        .map(|extension| {
+            let name = match extension {
+                "images" => "Synthetic v03 - Synthetic_ The Volume".to_string(),
+                extension => format!("Synthetic v03 - Synthetic_ The Volume.{}", extension),
+            };
+
+            Saved::Written(destination.join("Synthetic").join(name))
+        })
+        .collect();
+    let exists = expected.iter().all(|saved| match saved {
+        Saved::Written(path) | Saved::Skipped(path) => path.exists(),
+    });
+
+    let cbz = destination
+        .join("Synthetic")
+        .join("Synthetic v03 - Synthetic_ The Volume.cbz");
+    let names: Vec<String> = File::open(cbz)
+        .ok()
+        .and_then(|file| zip::ZipArchive::new(file).ok())
+        .map(|mut archive| {
+            (0..archive.len())
+                .filter_map(|index| Some(archive.by_index(index).ok()?.name().to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    std::fs::remove_dir_all(&destination).unwrap();
+
+    assert_eq!(saved.unwrap(), expected);
+    assert!(exists);
+    assert_eq!(
+        names,
+        ["0001.jpeg", "0002.jpeg", "0003.jpeg", "ComicInfo.xml"]
+    );
+}